@@ -0,0 +1,346 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error};
+use lighthouse_client::protocol::{Vec2, Zero};
+
+use crate::message::Action;
+
+/// A response curve that is applied to the magnitude of an analog input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseCurve {
+    Linear,
+    Quadratic,
+    Cubic,
+    Power(f64),
+}
+
+impl ResponseCurve {
+    /// Applies the curve to a magnitude in the range `0..=1`.
+    pub fn apply(self, magnitude: f64) -> f64 {
+        match self {
+            Self::Linear => magnitude,
+            Self::Quadratic => magnitude.powi(2),
+            Self::Cubic => magnitude.powi(3),
+            Self::Power(exponent) => magnitude.powf(exponent),
+        }
+    }
+}
+
+impl FromStr for ResponseCurve {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Self::Linear),
+            "quadratic" => Ok(Self::Quadratic),
+            "cubic" => Ok(Self::Cubic),
+            _ => {
+                let exponent: f64 = s.parse().map_err(|_| anyhow!("Invalid response curve '{s}' (expected linear, quadratic, cubic or an exponent)"))?;
+                if exponent <= 0.0 {
+                    bail!("Response curve exponent must be positive, but was {exponent}");
+                }
+                Ok(Self::Power(exponent))
+            },
+        }
+    }
+}
+
+/// Deadzone, response curve and inversion settings for a two-axis analog
+/// input such as a gamepad stick or the mouse.
+///
+/// Parsed from a comma-separated list of `key=value` pairs, e.g.
+/// `deadzone=0.1,outer=0.05,axial=0.05:0,curve=quadratic,invert=y,run=0.9`.
+/// Keys that are omitted keep their neutral default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnalogSettings {
+    /// The radius below which the input is treated as centered.
+    pub deadzone: f64,
+    /// The margin below full deflection above which the input is treated as
+    /// fully deflected.
+    pub outer_deadzone: f64,
+    /// Per-axis thresholds below which the respective component is dropped.
+    pub axial_deadzone: Vec2<f64>,
+    /// The curve applied to the magnitude after removing the deadzones.
+    pub curve: ResponseCurve,
+    /// Whether the respective axis is inverted.
+    pub invert: Vec2<bool>,
+    /// The (processed) magnitude at which the player starts running, if any.
+    pub run_threshold: Option<f64>,
+}
+
+impl AnalogSettings {
+    /// Applies inversion, deadzones and the response curve to the given raw
+    /// value. A processed value of zero means that the input is centered.
+    pub fn apply(&self, value: Vec2<f64>) -> Vec2<f64> {
+        let value = Vec2::new(
+            if self.invert.x { -value.x } else { value.x },
+            if self.invert.y { -value.y } else { value.y },
+        );
+        let value = Vec2::new(
+            if value.x.abs() < self.axial_deadzone.x { 0.0 } else { value.x },
+            if value.y.abs() < self.axial_deadzone.y { 0.0 } else { value.y },
+        );
+
+        let magnitude = value.length();
+        if magnitude == 0.0 || magnitude < self.deadzone {
+            return Vec2::ZERO;
+        }
+
+        let saturation = (1.0 - self.outer_deadzone).max(self.deadzone + f64::EPSILON);
+        let normalized = ((magnitude - self.deadzone) / (saturation - self.deadzone)).clamp(0.0, 1.0);
+        let scale = self.curve.apply(normalized) / magnitude;
+        value.map(|c| c * scale)
+    }
+
+    /// Whether the given processed value is far enough deflected to run.
+    pub fn is_running(&self, processed: Vec2<f64>) -> bool {
+        self.run_threshold.is_some_and(|threshold| processed.length() >= threshold)
+    }
+}
+
+impl Default for AnalogSettings {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.0,
+            outer_deadzone: 0.0,
+            axial_deadzone: Vec2::ZERO,
+            curve: ResponseCurve::Linear,
+            invert: Vec2::new(false, false),
+            run_threshold: None,
+        }
+    }
+}
+
+impl FromStr for AnalogSettings {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| anyhow!("Expected key=value, but got '{pair}'"))?;
+            let parse_f64 = |value: &str| value.parse::<f64>().with_context(|| format!("Invalid value '{value}' for '{key}'"));
+            match key {
+                "deadzone" => settings.deadzone = parse_f64(value)?,
+                "outer" => settings.outer_deadzone = parse_f64(value)?,
+                "axial" => settings.axial_deadzone = match value.split_once(':') {
+                    Some((x, y)) => Vec2::new(parse_f64(x)?, parse_f64(y)?),
+                    None => {
+                        let both = parse_f64(value)?;
+                        Vec2::new(both, both)
+                    },
+                },
+                "curve" => settings.curve = value.parse()?,
+                "invert" => settings.invert = match value {
+                    "none" => Vec2::new(false, false),
+                    "x" => Vec2::new(true, false),
+                    "y" => Vec2::new(false, true),
+                    "xy" => Vec2::new(true, true),
+                    _ => bail!("Invalid inversion '{value}' (expected none, x, y or xy)"),
+                },
+                "run" => settings.run_threshold = Some(parse_f64(value)?),
+                _ => bail!("Unknown analog setting '{key}'"),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// The value at which a trigger is considered pressed, unless configured
+/// otherwise.
+const DEFAULT_TRIGGER_THRESHOLD: f64 = 0.3;
+
+/// Actuation settings for an analog trigger.
+///
+/// Parsed from a comma-separated list of `key=value` pairs, e.g.
+/// `threshold=0.3,release=0.2,action=use,axis=4`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TriggerSettings {
+    /// The value at which the trigger is considered pressed.
+    pub threshold: f64,
+    /// The value below which a pressed trigger is considered released.
+    pub release: f64,
+    /// The action the trigger is bound to, if any.
+    pub action: Option<Action>,
+    /// The index of a single gamepad axis that reports this trigger, if any.
+    pub axis: Option<usize>,
+}
+
+impl TriggerSettings {
+    /// Whether the trigger is pressed at the given value, taking into account
+    /// whether it was pressed before.
+    pub fn is_pressed(&self, was_pressed: bool, value: f64) -> bool {
+        if was_pressed {
+            value >= self.release
+        } else {
+            value >= self.threshold
+        }
+    }
+}
+
+impl Default for TriggerSettings {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_TRIGGER_THRESHOLD,
+            release: DEFAULT_TRIGGER_THRESHOLD,
+            action: None,
+            axis: None,
+        }
+    }
+}
+
+impl FromStr for TriggerSettings {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = Self::default();
+        let mut release = None;
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| anyhow!("Expected key=value, but got '{pair}'"))?;
+            match key {
+                "threshold" => settings.threshold = value.parse().with_context(|| format!("Invalid threshold '{value}'"))?,
+                "release" => release = Some(value.parse().with_context(|| format!("Invalid release threshold '{value}'"))?),
+                "action" => settings.action = match value {
+                    "none" => None,
+                    "fire" => Some(Action::Fire),
+                    "use" => Some(Action::Use),
+                    "run" => Some(Action::Speed),
+                    _ => bail!("Invalid trigger action '{value}' (expected none, fire, use or run)"),
+                },
+                "axis" => settings.axis = Some(value.parse().with_context(|| format!("Invalid axis index '{value}'"))?),
+                _ => bail!("Unknown trigger setting '{key}'"),
+            }
+        }
+        settings.release = release.unwrap_or(settings.threshold);
+        // Triggers rest at 0, i.e. they'd be pressed (or never released) forever
+        // at 0 and couldn't be pressed at all above 1
+        if settings.threshold.is_nan() || settings.threshold <= 0.0 || settings.threshold > 1.0 {
+            bail!("Threshold must be greater than 0 and at most 1, but was {}", settings.threshold);
+        }
+        if settings.release.is_nan() || settings.release <= 0.0 {
+            bail!("Release threshold must be greater than 0, but was {}", settings.release);
+        }
+        if settings.release > settings.threshold {
+            bail!("Release threshold {} must not exceed the actuation threshold {}", settings.release, settings.threshold);
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lighthouse_client::protocol::{Vec2, Zero};
+
+    use crate::message::Action;
+
+    use super::{AnalogSettings, ResponseCurve, TriggerSettings};
+
+    fn assert_close(actual: Vec2<f64>, expected: Vec2<f64>) {
+        assert!((actual - expected).length() < 1e-9, "Expected {expected:?}, but got {actual:?}");
+    }
+
+    #[test]
+    fn analog_defaults() {
+        let settings: AnalogSettings = "".parse().unwrap();
+        assert_eq!(settings, AnalogSettings::default());
+    }
+
+    #[test]
+    fn analog_settings() {
+        let settings: AnalogSettings = "deadzone=0.1, outer=0.05, axial=0.05:0, curve=quadratic, invert=y, run=0.9".parse().unwrap();
+        assert_eq!(settings, AnalogSettings {
+            deadzone: 0.1,
+            outer_deadzone: 0.05,
+            axial_deadzone: Vec2::new(0.05, 0.0),
+            curve: ResponseCurve::Quadratic,
+            invert: Vec2::new(false, true),
+            run_threshold: Some(0.9),
+        });
+    }
+
+    #[test]
+    fn analog_axial_deadzone_for_both_axes() {
+        let settings: AnalogSettings = "axial=0.2".parse().unwrap();
+        assert_eq!(settings.axial_deadzone, Vec2::new(0.2, 0.2));
+    }
+
+    #[test]
+    fn analog_curves() {
+        assert_eq!("curve=linear".parse::<AnalogSettings>().unwrap().curve, ResponseCurve::Linear);
+        assert_eq!("curve=cubic".parse::<AnalogSettings>().unwrap().curve, ResponseCurve::Cubic);
+        assert_eq!("curve=1.5".parse::<AnalogSettings>().unwrap().curve, ResponseCurve::Power(1.5));
+    }
+
+    #[test]
+    fn invalid_analog_settings() {
+        for s in ["deadzone", "deadzone=lots", "axial=0.1:y", "curve=steep", "curve=0", "curve=-2", "invert=z", "foo=1"] {
+            assert!(s.parse::<AnalogSettings>().is_err(), "{s} should be invalid");
+        }
+    }
+
+    #[test]
+    fn analog_deadzones() {
+        let settings: AnalogSettings = "deadzone=0.2,outer=0.2".parse().unwrap();
+        assert_eq!(settings.apply(Vec2::new(0.1, 0.1)), Vec2::ZERO);
+        // The range between the deadzones is stretched to 0..=1
+        assert_close(settings.apply(Vec2::new(0.5, 0.0)), Vec2::new(0.5, 0.0));
+        assert_close(settings.apply(Vec2::new(0.0, -0.9)), Vec2::new(0.0, -1.0));
+    }
+
+    #[test]
+    fn analog_axial_deadzone_and_inversion() {
+        let settings: AnalogSettings = "axial=0.1:0,invert=x".parse().unwrap();
+        assert_close(settings.apply(Vec2::new(0.05, 0.5)), Vec2::new(0.0, 0.5));
+        assert_close(settings.apply(Vec2::new(0.5, 0.0)), Vec2::new(-0.5, 0.0));
+    }
+
+    #[test]
+    fn analog_curve_and_running() {
+        let settings: AnalogSettings = "curve=quadratic,run=0.5".parse().unwrap();
+        let processed = settings.apply(Vec2::new(0.5, 0.0));
+        assert_close(processed, Vec2::new(0.25, 0.0));
+        assert!(!settings.is_running(processed));
+        assert!(settings.is_running(settings.apply(Vec2::new(0.0, 1.0))));
+    }
+
+    #[test]
+    fn trigger_defaults() {
+        let settings: TriggerSettings = "".parse().unwrap();
+        assert_eq!(settings, TriggerSettings::default());
+    }
+
+    #[test]
+    fn trigger_settings() {
+        let settings: TriggerSettings = "threshold=0.5, release=0.2, action=fire, axis=4".parse().unwrap();
+        assert_eq!(settings, TriggerSettings { threshold: 0.5, release: 0.2, action: Some(Action::Fire), axis: Some(4) });
+    }
+
+    #[test]
+    fn trigger_release_defaults_to_threshold() {
+        let settings: TriggerSettings = "threshold=0.6".parse().unwrap();
+        assert_eq!(settings.release, 0.6);
+    }
+
+    #[test]
+    fn invalid_trigger_settings() {
+        for s in ["threshold", "threshold=high", "action=jump", "axis=-1", "foo=1"] {
+            assert!(s.parse::<TriggerSettings>().is_err(), "{s} should be invalid");
+        }
+    }
+
+    #[test]
+    fn trigger_thresholds_out_of_range() {
+        for s in ["threshold=0", "threshold=-0.5", "threshold=1.5", "threshold=NaN", "release=0", "threshold=0.3,release=0.5"] {
+            assert!(s.parse::<TriggerSettings>().is_err(), "{s} should be out of range");
+        }
+        assert!("threshold=1,release=0.01".parse::<TriggerSettings>().is_ok());
+    }
+
+    #[test]
+    fn trigger_hysteresis() {
+        let settings: TriggerSettings = "threshold=0.5,release=0.2".parse().unwrap();
+        assert!(!settings.is_pressed(false, 0.4));
+        assert!(settings.is_pressed(false, 0.5));
+        assert!(settings.is_pressed(true, 0.3));
+        assert!(!settings.is_pressed(true, 0.1));
+    }
+}
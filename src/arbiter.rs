@@ -0,0 +1,320 @@
+//! Arbitration between multiple input sources, e.g. someone at the GUI and
+//! someone on the Lighthouse frontend playing at the same time.
+//!
+//! The arbiter keeps track of the actions that every source holds down, so a
+//! source releasing a key never releases the same key held by another source.
+//! Depending on the policy, the sources either play together or one of them is
+//! in control, in which case the actions of the others are released.
+//!
+//! To keep keys from getting stuck (e.g. because a browser tab lost the
+//! key-up), the arbiter also releases everything a source holds on request and
+//! any action other than movement or firing that is held for too long.
+
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
+
+use clap::{Args, ValueEnum};
+use tracing::{debug, info};
+
+use crate::message::{Action, InputSource, InputSourceKind, MapperMessage};
+
+/// Settings for arbitrating between input sources.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Input arbitration")]
+pub struct ArbitrationOptions {
+    /// How to arbitrate between input sources that are used at the same time.
+    #[arg(long, env = "LIDOOM_INPUT_POLICY", value_enum, default_value_t = ArbitrationPolicy::Merge)]
+    pub input_policy: ArbitrationPolicy,
+    /// The kinds of input sources from highest to lowest priority, for the priority policy.
+    #[arg(long, env = "LIDOOM_INPUT_PRIORITY", value_enum, value_delimiter = ',', default_value = "gui,lighthouse,websocket")]
+    pub input_priority: Vec<InputSourceKind>,
+    /// Releases everything a source holds once it hasn't sent any input for this many seconds (0 disables).
+    #[arg(long, env = "LIDOOM_INPUT_TIMEOUT", default_value_t = 30)]
+    pub input_timeout: u64,
+    /// Releases any key other than movement or fire once it is held for this many seconds (0 disables).
+    #[arg(long, env = "LIDOOM_INPUT_MAX_HOLD", default_value_t = 15)]
+    pub input_max_hold: u64,
+}
+
+/// How to arbitrate between input sources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ArbitrationPolicy {
+    /// All sources play together, an action is only released once no source holds it anymore.
+    Merge,
+    /// The source that last pressed something is in control.
+    Exclusive,
+    /// The source with the highest priority (see --input-priority) that holds something is in control, ties going to the one in control already.
+    Priority,
+}
+
+/// Decides which of the mapped messages reach DOOM.
+#[derive(Debug)]
+pub struct Arbiter {
+    options: ArbitrationOptions,
+    /// The actions held down by every source and since when.
+    held: HashMap<InputSource, HashMap<Action, Instant>>,
+    /// Actions that were held for too long, which are ignored until the source
+    /// releases them.
+    expired: HashSet<(InputSource, Action)>,
+    /// The source in control, unless merging.
+    owner: Option<InputSource>,
+}
+
+impl Arbiter {
+    pub fn new(options: ArbitrationOptions) -> Self {
+        Self {
+            options,
+            held: HashMap::new(),
+            expired: HashSet::new(),
+            owner: None,
+        }
+    }
+
+    /// Arbitrates a message mapped from the given source's input, returning
+    /// the messages to send to DOOM.
+    pub fn arbitrate(&mut self, source: &InputSource, message: MapperMessage) -> Vec<MapperMessage> {
+        // Commands don't hold anything, so they always pass
+        let MapperMessage::Action { action, down } = message else { return vec![message] };
+
+        let key = (source.clone(), action);
+        if self.expired.contains(&key) {
+            if !down {
+                self.expired.remove(&key);
+            }
+            return Vec::new();
+        }
+
+        let held = self.held.entry(source.clone()).or_default();
+        if down {
+            held.entry(action).or_insert_with(Instant::now);
+        } else {
+            held.remove(&action);
+        }
+
+        if self.options.input_policy == ArbitrationPolicy::Merge {
+            let held_elsewhere = self.held.iter().any(|(other, held)| other != source && held.contains_key(&action));
+            return if down || !held_elsewhere { vec![message] } else { Vec::new() };
+        }
+
+        let mut out = Vec::new();
+        if self.owner.as_ref() == Some(source) {
+            out.push(message);
+        }
+
+        let owner = match self.options.input_policy {
+            ArbitrationPolicy::Exclusive if down => Some(source.clone()),
+            ArbitrationPolicy::Priority => self.held.iter()
+                .filter(|(_, held)| !held.is_empty())
+                .map(|(source, _)| source)
+                // Several sources may share a priority, in which case the owner keeps control
+                .min_by_key(|&source| (self.priority(source), self.owner.as_ref() != Some(source)))
+                .cloned()
+                .or_else(|| self.owner.clone()),
+            _ => self.owner.clone(),
+        };
+
+        if owner != self.owner {
+            debug!("Handing control over from {:?} to {:?}", self.owner, owner);
+            // Release what the previous owner held and press what the next one holds
+            if let Some(previous) = &self.owner {
+                out.extend(self.held_by(previous).map(|action| MapperMessage::Action { action, down: false }));
+            }
+            if let Some(next) = &owner {
+                out.extend(self.held_by(next).map(|action| MapperMessage::Action { action, down: true }));
+            }
+            self.owner = owner;
+        }
+
+        out
+    }
+
+    /// Whether the source holds down any action.
+    pub fn holds_any(&self, source: &InputSource) -> bool {
+        self.held.get(source).is_some_and(|held| !held.is_empty())
+    }
+
+    /// Releases everything the source holds, e.g. because it disconnected or
+    /// lost focus, returning the messages to send to DOOM.
+    pub fn release(&mut self, source: &InputSource) -> Vec<MapperMessage> {
+        self.expired.retain(|(s, _)| s != source);
+        let actions: Vec<Action> = self.held_by(source).collect();
+        let out = actions.into_iter()
+            .flat_map(|action| self.arbitrate(source, MapperMessage::Action { action, down: false }))
+            .collect();
+        // Sources come and go (e.g. every WebSocket connection is one), so we
+        // don't keep track of them once they hold nothing
+        self.held.remove(source);
+        out
+    }
+
+    /// Releases the actions other than movement and firing that are held for
+    /// longer than allowed, returning the messages to send to DOOM.
+    pub fn expire(&mut self, now: Instant) -> Vec<MapperMessage> {
+        if self.options.input_max_hold == 0 {
+            return Vec::new();
+        }
+        let max_hold = Duration::from_secs(self.options.input_max_hold);
+        let expired: Vec<(InputSource, Action)> = self.held.iter()
+            .flat_map(|(source, held)| held.iter().map(move |(&action, &since)| (source.clone(), action, since)))
+            .filter(|&(_, action, since)| !may_hold(action) && now.saturating_duration_since(since) >= max_hold)
+            .map(|(source, action, _)| (source, action))
+            .collect();
+        let mut out = Vec::new();
+        for (source, action) in expired {
+            info!("Releasing {action:?} held by {source:?} for more than {} s", max_hold.as_secs());
+            out.extend(self.arbitrate(&source, MapperMessage::Action { action, down: false }));
+            self.expired.insert((source, action));
+        }
+        out
+    }
+
+    fn held_by(&self, source: &InputSource) -> impl Iterator<Item = Action> + '_ {
+        self.held.get(source).into_iter().flat_map(|held| held.keys()).copied()
+    }
+
+    /// The rank of a source by its kind, with lower ranks taking precedence.
+    fn priority(&self, source: &InputSource) -> usize {
+        self.options.input_priority.iter()
+            .position(|&kind| kind == source.kind())
+            .unwrap_or(self.options.input_priority.len())
+    }
+}
+
+/// Whether the action may legitimately be held for a long time, i.e. it moves
+/// the player or fires (e.g. the chaingun).
+fn may_hold(action: Action) -> bool {
+    matches!(
+        action,
+        Action::Up | Action::Down | Action::Left | Action::Right
+            | Action::StrafeLeft | Action::StrafeRight | Action::Speed | Action::Strafe
+            | Action::Fire
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::message::{Action, Command, InputSource, InputSourceKind, MapperMessage};
+
+    use super::{Arbiter, ArbitrationOptions, ArbitrationPolicy};
+
+    fn arbiter(input_policy: ArbitrationPolicy) -> Arbiter {
+        Arbiter::new(ArbitrationOptions {
+            input_policy,
+            input_priority: vec![InputSourceKind::Gui, InputSourceKind::Lighthouse],
+            input_timeout: 30,
+            input_max_hold: 15,
+        })
+    }
+
+    fn press(action: Action) -> MapperMessage {
+        MapperMessage::Action { action, down: true }
+    }
+
+    fn release(action: Action) -> MapperMessage {
+        MapperMessage::Action { action, down: false }
+    }
+
+    #[test]
+    fn merge() {
+        let mut arbiter = arbiter(ArbitrationPolicy::Merge);
+        let (a, b) = (InputSource::Gui, InputSource::Lighthouse("a".to_owned()));
+        assert_eq!(arbiter.arbitrate(&a, press(Action::Fire)), [press(Action::Fire)]);
+        assert_eq!(arbiter.arbitrate(&b, press(Action::Fire)), [press(Action::Fire)]);
+        assert_eq!(arbiter.arbitrate(&b, press(Action::Up)), [press(Action::Up)]);
+        // Fire stays pressed until neither source holds it
+        assert_eq!(arbiter.arbitrate(&a, release(Action::Fire)), []);
+        assert_eq!(arbiter.arbitrate(&b, release(Action::Fire)), [release(Action::Fire)]);
+        assert!(arbiter.holds_any(&b));
+        assert!(!arbiter.holds_any(&a));
+    }
+
+    #[test]
+    fn commands_pass() {
+        for policy in [ArbitrationPolicy::Merge, ArbitrationPolicy::Exclusive, ArbitrationPolicy::Priority] {
+            let mut arbiter = arbiter(policy);
+            arbiter.arbitrate(&InputSource::Gui, press(Action::Fire));
+            let message = MapperMessage::Command(Command::Screenshot);
+            assert_eq!(arbiter.arbitrate(&InputSource::WebSocket(1), message), [message]);
+        }
+    }
+
+    #[test]
+    fn exclusive() {
+        let mut arbiter = arbiter(ArbitrationPolicy::Exclusive);
+        let (a, b) = (InputSource::WebSocket(1), InputSource::WebSocket(2));
+        assert_eq!(arbiter.arbitrate(&a, press(Action::Up)), [press(Action::Up)]);
+        // Pressing something hands control over, releasing what the previous owner held
+        assert_eq!(arbiter.arbitrate(&b, press(Action::Fire)), [release(Action::Up), press(Action::Fire)]);
+        assert_eq!(arbiter.arbitrate(&a, release(Action::Up)), []);
+        assert_eq!(arbiter.arbitrate(&a, press(Action::Left)), [release(Action::Fire), press(Action::Left)]);
+        // Releasing doesn't take control
+        assert_eq!(arbiter.arbitrate(&b, release(Action::Fire)), []);
+        assert_eq!(arbiter.arbitrate(&a, release(Action::Left)), [release(Action::Left)]);
+    }
+
+    #[test]
+    fn priority() {
+        let mut arbiter = arbiter(ArbitrationPolicy::Priority);
+        let (gui, lighthouse, websocket) = (InputSource::Gui, InputSource::Lighthouse("a".to_owned()), InputSource::WebSocket(1));
+        assert_eq!(arbiter.arbitrate(&lighthouse, press(Action::Up)), [press(Action::Up)]);
+        // Sources without a priority come last
+        assert_eq!(arbiter.arbitrate(&websocket, press(Action::Fire)), []);
+        assert_eq!(arbiter.arbitrate(&gui, press(Action::Left)), [release(Action::Up), press(Action::Left)]);
+        assert_eq!(arbiter.arbitrate(&lighthouse, release(Action::Up)), []);
+        // Once the GUI holds nothing, the next source in line takes over
+        assert_eq!(arbiter.arbitrate(&gui, release(Action::Left)), [release(Action::Left), press(Action::Fire)]);
+        assert_eq!(arbiter.arbitrate(&websocket, release(Action::Fire)), [release(Action::Fire)]);
+    }
+
+    #[test]
+    fn priority_ties() {
+        let mut arbiter = arbiter(ArbitrationPolicy::Priority);
+        let (a, b) = (InputSource::Lighthouse("a".to_owned()), InputSource::Lighthouse("b".to_owned()));
+        assert_eq!(arbiter.arbitrate(&a, press(Action::Up)), [press(Action::Up)]);
+        assert_eq!(arbiter.arbitrate(&b, press(Action::Fire)), []);
+        assert_eq!(arbiter.arbitrate(&b, release(Action::Fire)), []);
+        assert_eq!(arbiter.arbitrate(&a, release(Action::Up)), [release(Action::Up)]);
+    }
+
+    #[test]
+    fn release_all() {
+        let mut arbiter = arbiter(ArbitrationPolicy::Merge);
+        let (a, b) = (InputSource::WebSocket(1), InputSource::WebSocket(2));
+        arbiter.arbitrate(&a, press(Action::Up));
+        arbiter.arbitrate(&a, press(Action::Fire));
+        arbiter.arbitrate(&b, press(Action::Fire));
+        // Fire is still held by the other source
+        assert_eq!(arbiter.release(&a), [release(Action::Up)]);
+        assert!(!arbiter.holds_any(&a));
+        assert_eq!(arbiter.release(&a), []);
+        assert_eq!(arbiter.release(&b), [release(Action::Fire)]);
+    }
+
+    #[test]
+    fn expire() {
+        let mut arbiter = arbiter(ArbitrationPolicy::Merge);
+        let source = InputSource::Gui;
+        for action in [Action::Use, Action::Fire, Action::Up, Action::Speed] {
+            arbiter.arbitrate(&source, press(action));
+        }
+        let now = Instant::now();
+        assert_eq!(arbiter.expire(now), []);
+        // Only actions other than movement and firing are released
+        let later = now + Duration::from_secs(16);
+        assert_eq!(arbiter.expire(later), [release(Action::Use)]);
+        assert_eq!(arbiter.expire(later), []);
+
+        // The expired action is ignored until the source releases it
+        assert_eq!(arbiter.arbitrate(&source, press(Action::Use)), []);
+        assert_eq!(arbiter.arbitrate(&source, release(Action::Use)), []);
+        assert_eq!(arbiter.arbitrate(&source, press(Action::Use)), [press(Action::Use)]);
+    }
+
+    #[test]
+    fn expire_disabled() {
+        let mut arbiter = Arbiter::new(ArbitrationOptions { input_max_hold: 0, ..arbiter(ArbitrationPolicy::Merge).options });
+        arbiter.arbitrate(&InputSource::Gui, press(Action::Use));
+        assert_eq!(arbiter.expire(Instant::now() + Duration::from_secs(3600)), []);
+    }
+}
@@ -0,0 +1,125 @@
+//! An attract mode that shows demos while nobody is playing.
+
+use std::{ffi::{c_int, CStr}, path::PathBuf, time::{Duration, Instant}};
+
+use anyhow::{bail, Result};
+use clap::Args;
+use tracing::{info, warn};
+
+use crate::{demo, overlay::Overlay};
+
+/// The hint that is shown while in attract mode.
+const HINT: &str = "Press any key";
+
+/// Settings for the attract mode.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Attract mode")]
+pub struct AttractOptions {
+    /// The number of seconds without input after which demos are shown until someone presses a key (disabled by default).
+    #[arg(long, env = "LIDOOM_ATTRACT_AFTER")]
+    pub attract_after: Option<u64>,
+    /// Demos to cycle through in attract mode instead of the ones built into the WAD, e.g. recorded via --record-demo.
+    #[arg(long = "attract-demo", env = "LIDOOM_ATTRACT_DEMOS", value_delimiter = ',')]
+    pub attract_demos: Vec<PathBuf>,
+}
+
+/// Tracks whether anyone is playing and runs the attract mode. This lives on
+/// the DOOM thread, since it drives the engine via `demo`.
+#[derive(Debug)]
+pub struct AttractMode {
+    options: AttractOptions,
+    /// The configured demos, once they have been loaded.
+    demos: Option<Vec<&'static CStr>>,
+    next_demo: usize,
+    last_input: Instant,
+    /// The overlay and the game to start when leaving, while in attract mode.
+    active: Option<(Overlay, (c_int, c_int))>,
+}
+
+impl AttractMode {
+    pub fn new(options: AttractOptions) -> Self {
+        Self {
+            options,
+            demos: None,
+            next_demo: 0,
+            last_input: Instant::now(),
+            active: None,
+        }
+    }
+
+    /// Loads the configured demos, which requires DOOM to be initialized.
+    fn demos(&mut self) -> &[&'static CStr] {
+        self.demos.get_or_insert_with(|| self.options.attract_demos.iter()
+            .filter_map(|path| demo::load(path)
+                .inspect_err(|e| warn!("Could not load demo for attract mode: {e:#}"))
+                .ok())
+            .collect())
+    }
+
+    /// The overlay to draw onto the frames, if any.
+    pub fn overlay(&self) -> Option<&Overlay> {
+        self.active.as_ref().map(|(overlay, _)| overlay)
+    }
+
+    /// Registers a key press, returning whether it should be swallowed
+    /// because it only ended the attract mode.
+    pub fn handle_input(&mut self) -> bool {
+        self.last_input = Instant::now();
+        if let Some((_, (skill, episode))) = self.active.take() {
+            info!("Leaving attract mode");
+            demo::new_game(skill, episode);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Leaves attract mode without starting a game, e.g. because someone else
+    /// does, returning the game that was interrupted by it.
+    pub fn cancel(&mut self) -> Option<(c_int, c_int)> {
+        self.last_input = Instant::now();
+        self.active.take().map(|(_, game)| game)
+    }
+
+    /// Enters attract mode, unless demos are already being shown, played back
+    /// or recorded.
+    pub fn start(&mut self) -> Result<()> {
+        if self.active.is_some() {
+            bail!("Already in attract mode");
+        }
+        if demo::is_showing_demos() || demo::is_recording() {
+            bail!("Attract mode cannot interrupt demos");
+        }
+        info!("Entering attract mode");
+        self.active = Some((Overlay::new(HINT), demo::current_game()));
+        if self.demos().is_empty() {
+            demo::start_title_loop();
+        } else {
+            self.play_next_demo();
+        }
+        Ok(())
+    }
+
+    /// Enters attract mode after the configured idle time and keeps cycling
+    /// through the demos. Should be called once per frame.
+    pub fn update(&mut self) {
+        if self.active.is_none() {
+            let Some(secs) = self.options.attract_after else { return };
+            if self.last_input.elapsed() >= Duration::from_secs(secs) {
+                // Still wait for the full idle time if demos are playing anyway
+                self.last_input = Instant::now();
+                _ = self.start();
+            }
+        } else if !self.demos().is_empty() && !demo::is_playing_back() {
+            self.play_next_demo();
+        }
+    }
+
+    fn play_next_demo(&mut self) {
+        let index = self.next_demo;
+        let demos = self.demos();
+        let count = demos.len();
+        demo::play(demos[index % count]);
+        self.next_demo = (index + 1) % count;
+    }
+}
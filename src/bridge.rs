@@ -0,0 +1,89 @@
+//! A WebSocket bridge for input from the local network, e.g. from phones or
+//! custom controllers, which doesn't require a Lighthouse account.
+//!
+//! Clients connect to `/input` and send the same JSON `InputEvent`s that the
+//! Lighthouse server delivers, e.g.
+//!
+//! ```json
+//! {"type":"key","source":0,"code":"ArrowUp","down":true,"repeat":false,"modifiers":{"alt":false,"ctrl":false,"meta":false,"shift":false}}
+//! ```
+//!
+//! Every connection is an input source of its own. With the player queue, it is
+//! also a player of its own (who leaves the queue when it disconnects), unless
+//! it passes the ticket from the queue's web page, e.g. `/input?ticket=123`.
+//!
+//! For visitors without a controller of their own, `/controller` serves an
+//! on-screen gamepad for phones that sends its input through the bridge.
+
+use axum::{extract::{ws::{Message, WebSocket}, Query, State, WebSocketUpgrade}, http::StatusCode, response::{Html, IntoResponse, Response}, routing::get, Router};
+use lighthouse_client::protocol::InputEvent;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{controller, message::{ControllerInput, ControllerMessage, InputSource, PlayerId, QueueMessage}, queue};
+
+/// Where the bridge sends the input to.
+#[derive(Debug, Clone)]
+pub enum BridgeTarget {
+    Mapper(mpsc::Sender<ControllerInput>),
+    Queue(mpsc::Sender<QueueMessage>),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct ConnectQuery {
+    ticket: Option<u64>,
+}
+
+/// The routes for sending input via WebSockets and the on-screen gamepad.
+pub fn routes(target: BridgeTarget) -> Router {
+    Router::new()
+        .route("/controller", get(|| async { Html(include_str!("../static/controller.html")) }))
+        .route("/input", get(connect))
+        .with_state(target)
+}
+
+async fn connect(State(target): State<BridgeTarget>, Query(query): Query<ConnectQuery>, upgrade: WebSocketUpgrade) -> Response {
+    // Tickets only identify players in the queue, otherwise every connection gets its own
+    let page_ticket = query.ticket.filter(|_| matches!(target, BridgeTarget::Queue(_)));
+    let ticket = match page_ticket.map_or_else(queue::new_ticket, Ok) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            warn!("Could not create a ticket: {e:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        },
+    };
+    upgrade.on_upgrade(move |socket| read_input(socket, ticket, page_ticket.is_some(), target))
+}
+
+async fn read_input(mut socket: WebSocket, ticket: u64, from_page: bool, target: BridgeTarget) {
+    info!("Input client connected via WebSocket");
+    while let Some(Ok(message)) = socket.recv().await {
+        let Message::Text(text) = message else { continue };
+        let input_event: InputEvent = match serde_json::from_str(&text) {
+            Ok(input_event) => input_event,
+            Err(e) => {
+                debug!("Ignoring invalid input event from WebSocket: {e}");
+                continue;
+            },
+        };
+        let Some(message) = controller::convert_input_event(&input_event) else { continue };
+        if send(&target, ticket, message).await.is_err() {
+            break;
+        }
+    }
+    info!("Input client disconnected from WebSocket");
+    // The client can't release its keys anymore, so we'll do it for them
+    _ = send(&target, ticket, ControllerMessage::Release).await;
+    // Players from the web page may reconnect and leave via the page instead
+    if let (BridgeTarget::Queue(tx), false) = (&target, from_page) {
+        _ = tx.send(QueueMessage::Leave { player: PlayerId::Web(ticket) }).await;
+    }
+}
+
+async fn send(target: &BridgeTarget, ticket: u64, message: ControllerMessage) -> Result<(), ()> {
+    match target {
+        BridgeTarget::Mapper(tx) => tx.send(ControllerInput { source: InputSource::WebSocket(ticket), message }).await.map_err(|_| ()),
+        BridgeTarget::Queue(tx) => tx.send(QueueMessage::Input { player: PlayerId::Web(ticket), message }).await.map_err(|_| ()),
+    }
+}
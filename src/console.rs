@@ -0,0 +1,33 @@
+use anyhow::Result;
+use tokio::{io::{self, AsyncBufReadExt, BufReader}, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::message::{Command, MapperMessage};
+
+/// The commands that can be entered on stdin, along with their descriptions.
+const COMMANDS: &[(&str, Command, &str)] = &[
+    ("screenshot", Command::Screenshot, "Saves a screenshot"),
+    ("record-demo", Command::RecordDemo, "Restarts the level and records a demo"),
+    ("stop-demo", Command::StopDemo, "Stops recording the demo and saves it"),
+    ("attract", Command::Attract, "Shows demos until someone presses a key"),
+];
+
+pub async fn run(tx: mpsc::Sender<MapperMessage>) -> Result<()> {
+    let mut lines = BufReader::new(io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let input = line.trim();
+        if input.is_empty() {
+            continue;
+        }
+        if input == "help" {
+            for (name, _, description) in COMMANDS {
+                info!("{name:<12} {description}");
+            }
+        } else if let Some(&(_, command, _)) = COMMANDS.iter().find(|(name, _, _)| *name == input) {
+            tx.send(MapperMessage::Command(command)).await?;
+        } else {
+            warn!("Unknown command '{input}', type 'help' for a list of commands");
+        }
+    }
+    Ok(())
+}
@@ -2,3 +2,5 @@ use doomgeneric::game::{DOOMGENERIC_RESX, DOOMGENERIC_RESY};
 
 pub const DOOM_WIDTH: usize = DOOMGENERIC_RESX;
 pub const DOOM_HEIGHT: usize = DOOMGENERIC_RESY;
+/// The rate at which DOOM runs its game logic, i.e. `TICRATE`.
+pub const DOOM_TICK_RATE: u32 = 35;
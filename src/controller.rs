@@ -1,46 +1,76 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
 use futures::{prelude::*, Stream};
-use lighthouse_client::protocol::{Direction, GamepadAxis2DEvent, GamepadButtonEvent, GamepadControlEvent, InputEvent, KeyEvent, MouseButton as LighthouseMouseButton, MouseEvent, ServerMessage};
+use lighthouse_client::protocol::{Direction, GamepadAxis2DEvent, GamepadAxisEvent, GamepadButtonEvent, GamepadControlEvent, InputEvent, KeyEvent, MouseButton as LighthouseMouseButton, MouseEvent, ServerMessage};
 use tokio::sync::mpsc;
 
-use crate::message::{ControllerMessage, GamepadButton, GamepadStick, GamepadTrigger, Key, MouseButton};
+use crate::message::{ControllerInput, ControllerMessage, GamepadButton, GamepadStick, GamepadTrigger, InputSource, Key, MouseButton, PlayerId, QueueMessage};
 
 pub async fn run(
     mut stream: impl Stream<Item = lighthouse_client::Result<ServerMessage<InputEvent>>> + Unpin,
-    tx: mpsc::Sender<ControllerMessage>,
+    tx: mpsc::Sender<ControllerInput>,
+) -> Result<()> {
+    // Every client (e.g. browser tab) of the frontend is a source of its own
+    let mut clients = HashSet::new();
+    while let Some(msg) = stream.next().await {
+        let input_event = msg?.payload;
+        if let Some(message) = convert_input_event(&input_event) {
+            let client = input_event.source().to_string();
+            clients.insert(client.clone());
+            tx.send(ControllerInput { source: InputSource::Lighthouse(client), message }).await?;
+        }
+    }
+
+    // Nobody can release their keys after the connection is closed
+    for client in clients {
+        tx.send(ControllerInput { source: InputSource::Lighthouse(client), message: ControllerMessage::Release }).await?;
+    }
+    Ok(())
+}
+
+/// Like `run`, but sends the messages to the queue along with the client they
+/// came from, which decides whether they are passed on to the mapper.
+pub async fn run_queued(
+    mut stream: impl Stream<Item = lighthouse_client::Result<ServerMessage<InputEvent>>> + Unpin,
+    tx: mpsc::Sender<QueueMessage>,
 ) -> Result<()> {
     while let Some(msg) = stream.next().await {
         let input_event = msg?.payload;
+        if let Some(message) = convert_input_event(&input_event) {
+            let player = PlayerId::Lighthouse(input_event.source().to_string());
+            tx.send(QueueMessage::Input { player, message }).await?;
+        }
+    }
 
-        match input_event {
-            InputEvent::Mouse(MouseEvent { movement, button, down, pointer_locked, .. }) => {
-                if let Some(button) = convert_mouse_button(button) {
-                    tx.send(ControllerMessage::Mouse { movement, button, down, pointer_locked }).await?;
+    Ok(())
+}
+
+pub fn convert_input_event(input_event: &InputEvent) -> Option<ControllerMessage> {
+    match input_event {
+        InputEvent::Mouse(MouseEvent { movement, button, down, pointer_locked, .. }) => {
+            convert_mouse_button(button.clone()).map(|button| ControllerMessage::Mouse { movement: *movement, button, down: *down, pointer_locked: *pointer_locked })
+        },
+        InputEvent::Key(KeyEvent { code, down, .. }) => {
+            convert_key(code).map(|key| ControllerMessage::Key { key, down: *down })
+        },
+        InputEvent::Gamepad(gamepad) => match gamepad.control {
+            GamepadControlEvent::Button(GamepadButtonEvent { index, down, value }) => {
+                if let Some(trigger) = convert_gamepad_trigger(index) {
+                    Some(ControllerMessage::GamepadTrigger { trigger, value })
+                } else {
+                    convert_gamepad_button(index).map(|button| ControllerMessage::GamepadButton { button, down })
                 }
             },
-            InputEvent::Key(KeyEvent { code, down, .. }) => {
-                if let Some(key) = convert_key(&code) {
-                    tx.send(ControllerMessage::Key { key, down }).await?;
-                }
+            GamepadControlEvent::Axis(GamepadAxisEvent { index, value }) => {
+                Some(ControllerMessage::GamepadAxis { index, value })
             },
-            InputEvent::Gamepad(gamepad) => match gamepad.control {
-                GamepadControlEvent::Button(GamepadButtonEvent { index, down, .. }) => {
-                    if let Some(button) = convert_gamepad_button(index) {
-                        tx.send(ControllerMessage::GamepadButton { button, down }).await?;
-                    }
-                },
-                GamepadControlEvent::Axis2D(GamepadAxis2DEvent { index, value }) => {
-                    if let Some(stick) = convert_gamepad_axis2d(index) {
-                        tx.send(ControllerMessage::GamepadStick { stick, value }).await?;
-                    }
-                },
-                _ => {},
+            GamepadControlEvent::Axis2D(GamepadAxis2DEvent { index, value }) => {
+                convert_gamepad_axis2d(index).map(|stick| ControllerMessage::GamepadStick { stick, value })
             },
-            _ => {},
-        }
+        },
+        _ => None,
     }
-
-    Ok(())
 }
 
 fn convert_mouse_button(button: LighthouseMouseButton) -> Option<MouseButton> {
@@ -52,7 +82,8 @@ fn convert_mouse_button(button: LighthouseMouseButton) -> Option<MouseButton> {
     }
 }
 
-fn convert_key(js_key: &str) -> Option<Key> {
+pub fn convert_key(js_key: &str) -> Option<Key> {
+    // See https://www.w3.org/TR/uievents-code/
     match js_key {
         "ArrowLeft" => Some(Key::ArrowLeft),
         "ArrowRight" => Some(Key::ArrowRight),
@@ -63,30 +94,97 @@ fn convert_key(js_key: &str) -> Option<Key> {
         "ShiftLeft" => Some(Key::Shift),
         "ShiftRight" => Some(Key::Shift),
         "Space" => Some(Key::Space),
-        "CtrlLeft" => Some(Key::Ctrl),
-        "CtrlRight" => Some(Key::Ctrl),
-        _ if js_key.starts_with("Digit") => Some(Key::Letter(js_key.as_bytes()[5] as char)),
-        _ if js_key.starts_with("Key") => Some(Key::Letter(js_key.as_bytes()[3] as char)),
-        _ => None,
-        // TODO: Map more keys
+        "ControlLeft" => Some(Key::Ctrl),
+        "ControlRight" => Some(Key::Ctrl),
+        "AltLeft" => Some(Key::Alt),
+        "AltRight" => Some(Key::Alt),
+        "MetaLeft" => Some(Key::Meta),
+        "MetaRight" => Some(Key::Meta),
+        "Tab" => Some(Key::Tab),
+        "Backspace" => Some(Key::Backspace),
+        "CapsLock" => Some(Key::CapsLock),
+        "NumLock" => Some(Key::NumLock),
+        "ScrollLock" => Some(Key::ScrollLock),
+        "PrintScreen" => Some(Key::PrintScreen),
+        "Pause" => Some(Key::Pause),
+        "Insert" => Some(Key::Insert),
+        "Delete" => Some(Key::Delete),
+        "Home" => Some(Key::Home),
+        "End" => Some(Key::End),
+        "PageUp" => Some(Key::PageUp),
+        "PageDown" => Some(Key::PageDown),
+        "ContextMenu" => Some(Key::ContextMenu),
+        "Backquote" => Some(Key::Symbol('`')),
+        "Backslash" => Some(Key::Symbol('\\')),
+        "IntlBackslash" => Some(Key::Symbol('\\')),
+        "BracketLeft" => Some(Key::Symbol('[')),
+        "BracketRight" => Some(Key::Symbol(']')),
+        "Comma" => Some(Key::Symbol(',')),
+        "Equal" => Some(Key::Symbol('=')),
+        "Minus" => Some(Key::Symbol('-')),
+        "Period" => Some(Key::Symbol('.')),
+        "Quote" => Some(Key::Symbol('\'')),
+        "Semicolon" => Some(Key::Symbol(';')),
+        "Slash" => Some(Key::Symbol('/')),
+        "NumpadAdd" => Some(Key::Numpad('+')),
+        "NumpadSubtract" => Some(Key::Numpad('-')),
+        "NumpadMultiply" => Some(Key::Numpad('*')),
+        "NumpadStar" => Some(Key::Numpad('*')),
+        "NumpadDivide" => Some(Key::Numpad('/')),
+        "NumpadDecimal" => Some(Key::Numpad('.')),
+        "NumpadComma" => Some(Key::Numpad(',')),
+        "NumpadEqual" => Some(Key::Numpad('=')),
+        "NumpadEnter" => Some(Key::Enter),
+        "NumpadBackspace" => Some(Key::Backspace),
+        _ => {
+            let single_char = |suffix: &str| {
+                let mut chars = suffix.chars();
+                chars.next().filter(|_| chars.next().is_none())
+            };
+            if let Some(c) = js_key.strip_prefix("Key").and_then(single_char).filter(char::is_ascii_uppercase) {
+                Some(Key::Letter(c))
+            } else if let Some(c) = js_key.strip_prefix("Digit").and_then(single_char).filter(char::is_ascii_digit) {
+                Some(Key::Digit(c))
+            } else if let Some(c) = js_key.strip_prefix("Numpad").and_then(single_char).filter(char::is_ascii_digit) {
+                Some(Key::Numpad(c))
+            } else {
+                js_key.strip_prefix('F')
+                    .and_then(|n| n.parse::<u8>().ok())
+                    .filter(|n| (1..=24).contains(n))
+                    .map(Key::Function)
+            }
+        },
     }
 }
 
-fn convert_gamepad_button(button_idx: usize) -> Option<GamepadButton> {
+pub fn convert_gamepad_button(button_idx: usize) -> Option<GamepadButton> {
     // See https://www.w3.org/TR/gamepad/#dfn-standard-gamepad
     match button_idx {
         0 => Some(GamepadButton::Cluster(Direction::Down)),
         1 => Some(GamepadButton::Cluster(Direction::Right)),
         2 => Some(GamepadButton::Cluster(Direction::Left)),
         3 => Some(GamepadButton::Cluster(Direction::Up)),
-        6 => Some(GamepadButton::Trigger(GamepadTrigger::Left)),
-        7 => Some(GamepadButton::Trigger(GamepadTrigger::Right)),
+        4 => Some(GamepadButton::Bumper(Direction::Left)),
+        5 => Some(GamepadButton::Bumper(Direction::Right)),
+        8 => Some(GamepadButton::Select),
         9 => Some(GamepadButton::Menu),
+        10 => Some(GamepadButton::Stick(GamepadStick::Left)),
+        11 => Some(GamepadButton::Stick(GamepadStick::Right)),
         12 => Some(GamepadButton::DPad(Direction::Up)),
         13 => Some(GamepadButton::DPad(Direction::Down)),
         14 => Some(GamepadButton::DPad(Direction::Left)),
         15 => Some(GamepadButton::DPad(Direction::Right)),
+        16 => Some(GamepadButton::Home),
+
+        _ => None,
+    }
+}
 
+fn convert_gamepad_trigger(button_idx: usize) -> Option<GamepadTrigger> {
+    // The standard gamepad reports the (analog) triggers as buttons
+    match button_idx {
+        6 => Some(GamepadTrigger::Left),
+        7 => Some(GamepadTrigger::Right),
         _ => None,
     }
 }
@@ -99,3 +197,31 @@ fn convert_gamepad_axis2d(axis2d_idx: usize) -> Option<GamepadStick> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::message::Key;
+
+    use super::convert_key;
+
+    #[test]
+    fn keys() {
+        assert_eq!(convert_key("ArrowUp"), Some(Key::ArrowUp));
+        assert_eq!(convert_key("ShiftRight"), Some(Key::Shift));
+        assert_eq!(convert_key("NumpadEnter"), Some(Key::Enter));
+        assert_eq!(convert_key("KeyW"), Some(Key::Letter('W')));
+        assert_eq!(convert_key("Digit0"), Some(Key::Digit('0')));
+        assert_eq!(convert_key("Numpad7"), Some(Key::Numpad('7')));
+        assert_eq!(convert_key("NumpadAdd"), Some(Key::Numpad('+')));
+        assert_eq!(convert_key("Backquote"), Some(Key::Symbol('`')));
+        assert_eq!(convert_key("F1"), Some(Key::Function(1)));
+        assert_eq!(convert_key("F24"), Some(Key::Function(24)));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        for s in ["", "Key", "KeyWW", "Keyw", "Key1", "Digit", "DigitA", "Digit10", "Numpad", "NumpadX", "F", "F0", "F25", "F-1", "Fn", "Unidentified"] {
+            assert_eq!(convert_key(s), None, "{s} should be unknown");
+        }
+    }
+}
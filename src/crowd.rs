@@ -0,0 +1,314 @@
+//! A crowd mode, in which many people play together by voting on what to do.
+//!
+//! Votes come in as chat messages from an IRC channel or as messages from
+//! WebSocket clients (see `--web`), each simply being the name of an action,
+//! e.g. `fire` or `left`. Every voter has one vote per window, and the action
+//! that wins the window is held down during the next one.
+
+use std::{cmp::Reverse, collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use anyhow::{Context, Result};
+use axum::{extract::{ws::{Message, WebSocket}, State, WebSocketUpgrade}, response::Response, routing::get, Router};
+use clap::{Args, ValueEnum};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::TcpStream, sync::mpsc, time::{self, MissedTickBehavior}};
+use tracing::{info, warn};
+
+#[cfg(feature = "gui")]
+use tokio::sync::mpsc::error::TrySendError;
+#[cfg(feature = "gui")]
+use crate::message::{GUIMessage, VoteTally};
+use crate::message::{Action, CrowdMessage, MapperMessage};
+
+/// How long to wait before reconnecting to the IRC server.
+const IRC_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The words that vote for an action. The first word for an action is its
+/// name, e.g. in the GUI.
+const VOTES: &[(&str, Action)] = &[
+    ("up", Action::Up),
+    ("forward", Action::Up),
+    ("down", Action::Down),
+    ("back", Action::Down),
+    ("left", Action::Left),
+    ("right", Action::Right),
+    ("strafeleft", Action::StrafeLeft),
+    ("straferight", Action::StrafeRight),
+    ("fire", Action::Fire),
+    ("shoot", Action::Fire),
+    ("use", Action::Use),
+    ("open", Action::Use),
+    ("enter", Action::Enter),
+    ("escape", Action::Escape),
+    ("1", Action::KeyLetter('1')),
+    ("2", Action::KeyLetter('2')),
+    ("3", Action::KeyLetter('3')),
+    ("4", Action::KeyLetter('4')),
+    ("5", Action::KeyLetter('5')),
+    ("6", Action::KeyLetter('6')),
+    ("7", Action::KeyLetter('7')),
+];
+
+/// Settings for the crowd mode.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Crowd mode")]
+pub struct CrowdOptions {
+    /// Lets the crowd play by voting via IRC (see --crowd-irc) or WebSockets (at /crowd, see --web).
+    #[arg(long, env = "LIDOOM_CROWD")]
+    pub crowd: Option<VoteMode>,
+    /// The length of a voting window in milliseconds.
+    #[arg(long, env = "LIDOOM_CROWD_WINDOW", default_value_t = 1000)]
+    pub crowd_window: u64,
+    /// The address of an IRC server to read votes from, e.g. localhost:6667.
+    #[arg(long, env = "LIDOOM_CROWD_IRC", requires = "crowd")]
+    pub crowd_irc: Option<String>,
+    /// The IRC channel to read votes from.
+    #[arg(long, env = "LIDOOM_CROWD_IRC_CHANNEL", default_value = "#lidoom")]
+    pub crowd_irc_channel: String,
+    /// The nickname to join the IRC channel as.
+    #[arg(long, env = "LIDOOM_CROWD_IRC_NICK", default_value = "lidoom")]
+    pub crowd_irc_nick: String,
+}
+
+/// How the winner of a voting window is determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VoteMode {
+    /// The action with the most votes wins.
+    Majority,
+    /// A random action wins, weighted by its votes, so minorities get their way every now and then.
+    Weighted,
+}
+
+/// Parses a vote, e.g. from a chat message.
+pub fn parse_vote(text: &str) -> Option<Action> {
+    let text = text.trim().trim_start_matches('!').to_ascii_lowercase();
+    VOTES.iter().find(|(word, _)| *word == text).map(|&(_, action)| action)
+}
+
+/// The name of an action that can be voted for.
+#[cfg(feature = "gui")]
+pub fn vote_name(action: Action) -> &'static str {
+    VOTES.iter().find(|&&(_, a)| a == action).map_or("?", |(word, _)| word)
+}
+
+/// Tallies the ballots, with the actions sorted by their votes (and the order
+/// in `VOTES` on ties).
+fn tally(ballots: &HashMap<String, Action>) -> Vec<(Action, usize)> {
+    let mut votes: Vec<(Action, usize)> = Vec::new();
+    for &action in ballots.values() {
+        match votes.iter_mut().find(|(a, _)| *a == action) {
+            Some((_, count)) => *count += 1,
+            None => votes.push((action, 1)),
+        }
+    }
+    let order = |action: Action| VOTES.iter().position(|&(_, a)| a == action);
+    votes.sort_by_key(|&(action, count)| (Reverse(count), order(action)));
+    votes
+}
+
+fn pick_winner(votes: &[(Action, usize)], mode: VoteMode) -> Result<Option<Action>> {
+    match mode {
+        VoteMode::Majority => Ok(votes.first().map(|&(action, _)| action)),
+        VoteMode::Weighted => {
+            let total: usize = votes.iter().map(|(_, count)| count).sum();
+            if total == 0 {
+                return Ok(None);
+            }
+            let mut bytes = [0u8; 8];
+            getrandom::getrandom(&mut bytes)?;
+            let mut pick = (u64::from_le_bytes(bytes) % total as u64) as usize;
+            Ok(votes.iter().find(|&&(_, count)| {
+                let found = pick < count;
+                pick = pick.saturating_sub(count);
+                found
+            }).map(|&(action, _)| action))
+        },
+    }
+}
+
+/// Counts the votes and holds down the winning action of every window.
+pub async fn run(
+    mut rx: mpsc::Receiver<CrowdMessage>,
+    tx: mpsc::Sender<MapperMessage>,
+    #[cfg(feature = "gui")]
+    gui_tx: mpsc::Sender<GUIMessage>,
+    mode: VoteMode,
+    options: CrowdOptions,
+) -> Result<()> {
+    let mut ballots: HashMap<String, Action> = HashMap::new();
+    let mut winner: Option<Action> = None;
+    let mut interval = time::interval(Duration::from_millis(options.crowd_window));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    info!("Counting crowd votes every {} ms", options.crowd_window);
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(CrowdMessage::Vote { voter, action }) => {
+                    // Only the latest vote of every voter counts
+                    ballots.insert(voter, action);
+                },
+                None => break,
+            },
+            _ = interval.tick() => {
+                let votes = tally(&ballots);
+                let next_winner = pick_winner(&votes, mode)?;
+                ballots.clear();
+                if next_winner != winner {
+                    if let Some(action) = winner {
+                        tx.send(MapperMessage::Action { action, down: false }).await?;
+                    }
+                    if let Some(action) = next_winner {
+                        tx.send(MapperMessage::Action { action, down: true }).await?;
+                    }
+                    winner = next_winner;
+                }
+
+                // Only once per window and without waiting, since DOOM sends its
+                // frames through the same channel and a flood of votes would stall it
+                #[cfg(feature = "gui")]
+                match gui_tx.try_send(GUIMessage::Vote(VoteTally { votes, winner })) {
+                    Ok(()) | Err(TrySendError::Full(_)) => {},
+                    Err(TrySendError::Closed(_)) => break,
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Joins the IRC channel and turns its messages into votes, reconnecting if
+/// the connection is lost.
+pub async fn run_irc(tx: mpsc::Sender<CrowdMessage>, address: String, options: CrowdOptions) -> Result<()> {
+    loop {
+        match read_irc(&address, &options, &tx).await {
+            Ok(()) => warn!("The IRC server closed the connection, reconnecting..."),
+            Err(e) => warn!("Lost the connection to the IRC server, reconnecting: {e:#}"),
+        }
+        if tx.is_closed() {
+            return Ok(());
+        }
+        time::sleep(IRC_RECONNECT_DELAY).await;
+    }
+}
+
+async fn read_irc(address: &str, options: &CrowdOptions, tx: &mpsc::Sender<CrowdMessage>) -> Result<()> {
+    let stream = TcpStream::connect(address).await.with_context(|| format!("Could not connect to {address}"))?;
+    let (reader, mut writer) = stream.into_split();
+    let nick = &options.crowd_irc_nick;
+    let channel = &options.crowd_irc_channel;
+    writer.write_all(format!("NICK {nick}\r\nUSER {nick} 0 * :lidoom\r\nJOIN {channel}\r\n").as_bytes()).await?;
+    info!("Reading crowd votes from {channel} on {address}");
+
+    // Chat messages aren't necessarily valid UTF-8, which shouldn't cost the connection
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer).await? == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if let Some(token) = line.strip_prefix("PING ") {
+            writer.write_all(format!("PONG {token}\r\n").as_bytes()).await?;
+            continue;
+        }
+        // Chat messages look like `:nick!user@host PRIVMSG #channel :text`
+        let Some((prefix, rest)) = line.strip_prefix(':').and_then(|line| line.split_once(' ')) else { continue };
+        let Some((target, text)) = rest.strip_prefix("PRIVMSG ").and_then(|rest| rest.split_once(" :")) else { continue };
+        if !target.eq_ignore_ascii_case(channel) {
+            continue;
+        }
+        let sender = prefix.split('!').next().unwrap_or(prefix);
+        if let Some(action) = parse_vote(text) {
+            tx.send(CrowdMessage::Vote { voter: format!("irc:{sender}"), action }).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The state shared by the WebSocket handlers.
+#[derive(Debug, Clone)]
+struct WebState {
+    tx: mpsc::Sender<CrowdMessage>,
+    next_client: Arc<AtomicU64>,
+}
+
+/// The route for voting via WebSockets, which takes text messages with one
+/// vote each.
+pub fn routes(tx: mpsc::Sender<CrowdMessage>) -> Router {
+    let state = WebState { tx, next_client: Arc::new(AtomicU64::new(0)) };
+    Router::new()
+        .route("/crowd", get(connect))
+        .with_state(state)
+}
+
+async fn connect(State(state): State<WebState>, upgrade: WebSocketUpgrade) -> Response {
+    let client = state.next_client.fetch_add(1, Ordering::Relaxed);
+    upgrade.on_upgrade(move |socket| read_websocket(socket, client, state.tx))
+}
+
+async fn read_websocket(mut socket: WebSocket, client: u64, tx: mpsc::Sender<CrowdMessage>) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let Message::Text(text) = message else { continue };
+        if let Some(action) = parse_vote(&text) {
+            if tx.send(CrowdMessage::Vote { voter: format!("websocket:{client}"), action }).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::message::Action;
+
+    use super::{parse_vote, pick_winner, tally, VoteMode};
+
+    #[test]
+    fn votes() {
+        assert_eq!(parse_vote("up"), Some(Action::Up));
+        assert_eq!(parse_vote("  !Forward "), Some(Action::Up));
+        assert_eq!(parse_vote("SHOOT"), Some(Action::Fire));
+        assert_eq!(parse_vote("3"), Some(Action::KeyLetter('3')));
+    }
+
+    #[test]
+    fn invalid_votes() {
+        for s in ["", "!", "upp", "fire now", "8", "idkfa"] {
+            assert_eq!(parse_vote(s), None, "{s} should be invalid");
+        }
+    }
+
+    #[test]
+    fn tallies() {
+        let ballots: HashMap<String, Action> = [
+            ("a", Action::Fire),
+            ("b", Action::Left),
+            ("c", Action::Fire),
+            ("d", Action::Use),
+            ("e", Action::Up),
+        ].into_iter().map(|(voter, action)| (voter.to_owned(), action)).collect();
+        // Ties are broken by the order of the votes
+        let votes = tally(&ballots);
+        assert_eq!(votes, [(Action::Fire, 2), (Action::Up, 1), (Action::Left, 1), (Action::Use, 1)]);
+        assert_eq!(pick_winner(&votes, VoteMode::Majority).unwrap(), Some(Action::Fire));
+        assert!(tally(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn winners() {
+        for mode in [VoteMode::Majority, VoteMode::Weighted] {
+            assert_eq!(pick_winner(&[], mode).unwrap(), None);
+            assert_eq!(pick_winner(&[(Action::Use, 3)], mode).unwrap(), Some(Action::Use));
+        }
+        let votes = [(Action::Fire, 2), (Action::Up, 1)];
+        for _ in 0..20 {
+            let winner = pick_winner(&votes, VoteMode::Weighted).unwrap();
+            assert!(matches!(winner, Some(Action::Fire | Action::Up)));
+        }
+    }
+}
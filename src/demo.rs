@@ -0,0 +1,259 @@
+//! Recording and playing back DOOM's native demos (`.lmp` files).
+//!
+//! A demo stores the player's commands for every tic, starting at the
+//! beginning of a level, which makes it tiny and perfectly reproducible. Like
+//! in `state`, the functions in this module access the engine's C globals and
+//! must only be called from the DOOM thread.
+
+use std::{ffi::{c_char, c_int, c_uint, c_void, CStr, CString, OsString}, path::{Path, PathBuf}, sync::atomic::{AtomicI32, AtomicU32, Ordering}};
+
+use anyhow::{bail, Result};
+use clap::Args;
+
+/// Marks the end of the tics in a demo, see `g_game.c`.
+const DEMOMARKER: u8 = 0x80;
+/// The `gamestate_t` while playing a level.
+const GS_LEVEL: c_int = 0;
+/// The `gamestate_t` while showing a page of the title loop, e.g. the title
+/// screen or the credits.
+const GS_DEMOSCREEN: c_int = 3;
+/// The `gameaction_t` of a pending demo playback.
+const GA_PLAYDEMO: c_int = 5;
+
+/// The `lowres_turn` from before recording, which `G_BeginRecording` overrides
+/// for the demo's sake.
+static SAVED_LOWRES_TURN: AtomicI32 = AtomicI32::new(0);
+
+/// The number of demos loaded via `load`, which numbers their lumps.
+static LOADED_DEMOS: AtomicU32 = AtomicU32::new(0);
+
+/// Mirrors `lumpinfo_t` from `w_wad.h`.
+#[repr(C)]
+#[allow(dead_code)]
+struct LumpInfo {
+    name: [c_char; 8],
+    wad_file: *const c_void,
+    position: c_int,
+    size: c_int,
+    cache: *const c_void,
+    next: *const LumpInfo,
+}
+
+extern "C" {
+    static mut demorecording: c_int;
+    static demoplayback: c_int;
+    static mut advancedemo: c_int;
+    static demoname: *const c_char;
+    static demobuffer: *mut u8;
+    static mut demo_p: *mut u8;
+    static mut vanilla_demo_limit: c_int;
+    static mut lowres_turn: c_int;
+    static gamestate: c_int;
+    static gameaction: c_int;
+    static gameskill: c_int;
+    static gameepisode: c_int;
+    static gamemap: c_int;
+    static lumpinfo: *mut LumpInfo;
+    static numlumps: c_uint;
+
+    fn G_RecordDemo(name: *const c_char);
+    fn G_BeginRecording();
+    fn G_DeferedInitNew(skill: c_int, episode: c_int, map: c_int);
+    fn G_DeferedPlayDemo(name: *const c_char);
+    fn G_LoadGame(name: *const c_char);
+    fn D_StartTitle();
+    fn M_ClearMenus();
+    fn W_AddFile(filename: *const c_char) -> *const c_void;
+    fn W_GenerateHashTable();
+    fn M_WriteFile(name: *const c_char, source: *const c_void, length: c_int) -> c_int;
+    fn Z_Free(ptr: *mut c_void);
+}
+
+/// Settings for recording and playing back demos.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Demos")]
+pub struct DemoOptions {
+    /// A file to record a demo of the session to (this starts a new game right away).
+    #[arg(long, env = "LIDOOM_RECORD_DEMO", conflicts_with = "play_demo")]
+    pub record_demo: Option<PathBuf>,
+    /// A demo to play back instead of playing, either a file or the name of a demo in the WAD (e.g. demo1). Quits at the end.
+    #[arg(long, env = "LIDOOM_PLAY_DEMO")]
+    pub play_demo: Option<PathBuf>,
+    /// The directory to save demos recorded via the record-demo command to.
+    #[arg(long, env = "LIDOOM_DEMO_DIR", default_value = "demos")]
+    pub demo_dir: PathBuf,
+}
+
+impl DemoOptions {
+    /// The command-line arguments to pass to DOOM.
+    pub fn doom_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        if let Some(path) = &self.record_demo {
+            // DOOM appends the extension itself
+            args.extend(["-record".into(), without_lmp_extension(path).into()]);
+        }
+        if let Some(path) = &self.play_demo {
+            args.extend(["-playdemo".into(), path.into()]);
+        }
+        args
+    }
+}
+
+fn without_lmp_extension(path: &Path) -> PathBuf {
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("lmp")) {
+        path.with_extension("")
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Lifts the vanilla limit on the demo size, which would otherwise end the
+/// recording (and exit the game) after a few minutes.
+pub fn disable_size_limit() {
+    unsafe { vanilla_demo_limit = 0; }
+}
+
+/// Whether a demo is being recorded.
+pub fn is_recording() -> bool {
+    unsafe { demorecording != 0 }
+}
+
+/// Starts recording a demo to the given path. Since demos always start at the
+/// beginning of a level, this restarts the current level.
+pub fn start_recording(path: &Path) -> Result<()> {
+    if is_recording() {
+        bail!("Already recording a demo");
+    }
+    if !is_in_level() {
+        bail!("Demos can only be recorded while playing a level");
+    }
+    unsafe {
+        // DOOM copies the name (and appends the extension itself)
+        let name = CString::new(without_lmp_extension(path).into_os_string().into_encoded_bytes())?;
+        G_RecordDemo(name.as_ptr());
+        restart_level();
+        SAVED_LOWRES_TURN.store(lowres_turn, Ordering::Relaxed);
+        G_BeginRecording();
+    }
+    Ok(())
+}
+
+/// Finishes the demo being recorded and writes it, returning its path.
+pub fn stop_recording() -> Result<PathBuf> {
+    if !is_recording() {
+        bail!("Not recording a demo");
+    }
+    unsafe {
+        *demo_p = DEMOMARKER;
+        demo_p = demo_p.add(1);
+        let length = demo_p.offset_from(demobuffer) as c_int;
+        let path = PathBuf::from(CStr::from_ptr(demoname).to_string_lossy().into_owned());
+        let written = M_WriteFile(demoname, demobuffer as *const c_void, length) != 0;
+        Z_Free(demobuffer as *mut c_void);
+        demorecording = 0;
+        // Otherwise turning stays as coarse as in the demo
+        lowres_turn = SAVED_LOWRES_TURN.load(Ordering::Relaxed);
+        if !written {
+            bail!("Could not write demo to {}", path.display());
+        }
+        Ok(path)
+    }
+}
+
+/// Loads the demo file at the given path as a lump, returning the lump's name
+/// for playing it back. Every demo gets a unique lump name, e.g. `LD000001`.
+pub fn load(path: &Path) -> Result<&'static CStr> {
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wad")) {
+        bail!("{} is a WAD, not a demo", path.display());
+    }
+    let filename = CString::new(path.as_os_str().as_encoded_bytes())?;
+    unsafe {
+        if W_AddFile(filename.as_ptr()).is_null() {
+            bail!("Could not load {}", path.display());
+        }
+        // Files other than WADs are added as a single lump named after the first
+        // 8 characters of the file name, which neither tells demos with a common
+        // prefix apart nor keeps them from shadowing the WAD's lumps
+        let number = LOADED_DEMOS.fetch_add(1, Ordering::Relaxed) + 1;
+        let name = format!("LD{number:06}").into_bytes();
+        let lump_name = &mut (*lumpinfo.add(numlumps as usize - 1)).name;
+        for (c, &b) in lump_name.iter_mut().zip(&name) {
+            *c = b as c_char;
+        }
+        W_GenerateHashTable();
+        // DOOM keeps referring to the name while playing back the demo
+        Ok(Box::leak(CString::new(name)?.into_boxed_c_str()))
+    }
+}
+
+/// Whether a demo is being played back or the title loop is showing a page
+/// between demos.
+pub fn is_showing_demos() -> bool {
+    unsafe { demoplayback != 0 || gameaction == GA_PLAYDEMO || gamestate == GS_DEMOSCREEN }
+}
+
+/// Whether a level is being played (rather than e.g. a demo or the
+/// intermission).
+pub fn is_in_level() -> bool {
+    unsafe { gamestate == GS_LEVEL && demoplayback == 0 }
+}
+
+/// Whether a demo is being played back (or about to be).
+pub fn is_playing_back() -> bool {
+    unsafe { demoplayback != 0 || gameaction == GA_PLAYDEMO }
+}
+
+/// Starts DOOM's title loop, which cycles through the title screen and the
+/// demos built into the WAD.
+pub fn start_title_loop() {
+    unsafe {
+        M_ClearMenus();
+        D_StartTitle();
+    }
+}
+
+/// Plays back a demo loaded via `load`.
+pub fn play(name: &'static CStr) {
+    unsafe {
+        M_ClearMenus();
+        // Don't let the title loop continue once the previous demo has ended
+        advancedemo = 0;
+        G_DeferedPlayDemo(name.as_ptr());
+    }
+}
+
+/// The skill and episode of the current (or last) game.
+pub fn current_game() -> (c_int, c_int) {
+    unsafe { (gameskill, gameepisode) }
+}
+
+/// Restarts the current level.
+pub fn restart_level() {
+    unsafe {
+        M_ClearMenus();
+        advancedemo = 0;
+        G_DeferedInitNew(gameskill, gameepisode, gamemap);
+    }
+}
+
+/// Starts a new game at the first map of the given episode, e.g. to leave
+/// the title loop.
+pub fn new_game(skill: c_int, episode: c_int) {
+    unsafe {
+        M_ClearMenus();
+        advancedemo = 0;
+        G_DeferedInitNew(skill, episode, 1);
+    }
+}
+
+/// Loads a saved game, e.g. to leave the title loop.
+pub fn load_game(path: &Path) -> Result<()> {
+    let name = CString::new(path.as_os_str().as_encoded_bytes())?;
+    unsafe {
+        M_ClearMenus();
+        advancedemo = 0;
+        // DOOM copies the name
+        G_LoadGame(name.as_ptr());
+    }
+    Ok(())
+}
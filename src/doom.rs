@@ -1,38 +1,78 @@
-use std::process;
+use std::{ffi::{c_char, c_int, CString, OsString}, fs, iter, process, ptr};
 
-use doomgeneric::{game::DoomGeneric, input::{keys::{self, KEY_DOWN, KEY_ENTER, KEY_ESCAPE, KEY_FIRE, KEY_LEFT, KEY_RIGHT, KEY_SPEED, KEY_STRAFELEFT, KEY_STRAFERIGHT, KEY_UP, KEY_USE}, KeyData}};
-use lighthouse_client::protocol::{Color, Frame, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
-use tokio::sync::mpsc;
-use tracing::info;
+use chrono::Local;
+use doomgeneric::{game::{self, DoomGeneric}, input::{keys::{self, KEY_DOWN, KEY_ENTER, KEY_ESCAPE, KEY_FIRE, KEY_LEFT, KEY_RIGHT, KEY_SPEED, KEY_STRAFE, KEY_STRAFELEFT, KEY_STRAFERIGHT, KEY_UP, KEY_USE}, KeyData}};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn};
 
 #[cfg(feature = "gui")]
-use crate::message::GUIMessage;
-use crate::{constants::{DOOM_HEIGHT, DOOM_WIDTH}, message::{Action, MapperMessage, UpdaterMessage}};
+use crate::{message::GUIMessage, state};
+use crate::{attract::{AttractMode, AttractOptions}, constants::{DOOM_HEIGHT, DOOM_WIDTH}, demo::{self, DemoOptions}, handover::Handover, input_log::InputReplay, kiosk::{Kiosk, KioskOptions}, framebuffer::FramePool, message::{Action, Command, MapperMessage, ScreenshotMessage, UpdaterMessage}, publish::StatePublisher};
+
+/// The amount of damage at which game controllers rumble at full strength.
+#[cfg(feature = "gui")]
+const RUMBLE_MAX_DAMAGE: i32 = 40;
+/// How long game controllers rumble when the player takes damage.
+#[cfg(feature = "gui")]
+const RUMBLE_DURATION_MS: u32 = 250;
 
 pub struct LighthouseDoom {
     #[cfg(feature = "gui")]
     gui_tx: mpsc::Sender<GUIMessage>,
     updater_tx: mpsc::Sender<UpdaterMessage>,
+    output_txs: Vec<mpsc::Sender<UpdaterMessage>>,
+    screenshot_tx: mpsc::Sender<ScreenshotMessage>,
     mapper_tx: mpsc::Receiver<MapperMessage>,
+    demo_options: DemoOptions,
+    attract: AttractMode,
+    kiosk: Kiosk,
+    handover: Handover,
+    publisher: StatePublisher,
+    input_replay: InputReplay,
+    frame_pool: FramePool,
+    #[cfg(feature = "gui")]
+    last_damage_count: i32,
 }
 
 impl LighthouseDoom {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         #[cfg(feature = "gui")]
         gui_tx: mpsc::Sender<GUIMessage>,
         updater_tx: mpsc::Sender<UpdaterMessage>,
+        output_txs: Vec<mpsc::Sender<UpdaterMessage>>,
+        screenshot_tx: mpsc::Sender<ScreenshotMessage>,
         mapper_tx: mpsc::Receiver<MapperMessage>,
+        demo_options: DemoOptions,
+        attract_options: AttractOptions,
+        kiosk_options: KioskOptions,
+        handover: Handover,
+        publisher: StatePublisher,
+        input_replay: InputReplay,
     ) -> Self {
         Self {
             #[cfg(feature = "gui")]
             gui_tx,
             updater_tx,
-            mapper_tx
+            output_txs,
+            screenshot_tx,
+            mapper_tx,
+            demo_options,
+            attract: AttractMode::new(attract_options),
+            kiosk: Kiosk::new(kiosk_options),
+            handover,
+            publisher,
+            input_replay,
+            frame_pool: FramePool::new(),
+            #[cfg(feature = "gui")]
+            last_damage_count: 0,
         }
     }
 
     pub fn run(self) {
+        set_args(self.demo_options.doom_args());
         doomgeneric::game::init(self);
+        demo::disable_size_limit();
 
         loop {
             doomgeneric::game::tick();
@@ -45,47 +85,62 @@ impl DoomGeneric for LighthouseDoom {
         assert!(xres == DOOM_WIDTH);
         assert!(yres == DOOM_HEIGHT);
 
+        self.input_replay.update();
+        self.attract.update();
+        self.kiosk.update();
+        self.handover.update(&mut self.attract);
+        self.publisher.update(self.handover.player_number());
+        let overlay = self.attract.overlay().or(self.handover.overlay());
+        let frame = self.frame_pool.write(screen_buffer, overlay);
+
         #[cfg(feature = "gui")]
         {
             // Send frame to GUI
-            let mut screen_frame = vec![0u8; 3 * DOOM_WIDTH * DOOM_HEIGHT];
-            for y in 0..DOOM_HEIGHT {
-                for x in 0..DOOM_WIDTH {
-                    let pixel_idx = y * DOOM_WIDTH + x;
-                    let rgb_idx = 3 * pixel_idx;
-                    let pixel = screen_buffer[pixel_idx];
-                    screen_frame[rgb_idx] = ((pixel >> 16) & 0xFF) as u8; // red
-                    screen_frame[rgb_idx + 1] = ((pixel >> 8) & 0xFF) as u8; // green
-                    screen_frame[rgb_idx + 2] = (pixel & 0xFF) as u8; // blue
-                }
+            self.gui_tx.blocking_send(GUIMessage::Frame(frame.clone())).unwrap_or_else(|_| quit_upon_channel_close());
+
+            // Rumble connected game controllers when the player takes damage
+            let damage_count = state::player_status().damage_count;
+            if damage_count > self.last_damage_count {
+                let damage = (damage_count - self.last_damage_count).min(RUMBLE_MAX_DAMAGE);
+                let strength = (damage as u32 * u16::MAX as u32 / RUMBLE_MAX_DAMAGE as u32) as u16;
+                self.gui_tx.blocking_send(GUIMessage::Rumble { strength, duration_ms: RUMBLE_DURATION_MS }).unwrap_or_else(|_| quit_upon_channel_close());
             }
-            self.gui_tx.blocking_send(GUIMessage::Frame(screen_frame)).unwrap_or_else(|_| quit_upon_channel_close());
+            self.last_damage_count = damage_count;
         }
 
         // Send frame to updater (i.e. lighthouse)
-        let mut frame = Frame::empty();
-        for i in 0..LIGHTHOUSE_ROWS {
-            for j in 0..LIGHTHOUSE_COLS {
-                let y = (i * DOOM_HEIGHT) / LIGHTHOUSE_ROWS;
-                let x = (j * DOOM_WIDTH) / LIGHTHOUSE_COLS;
-                let pixel = screen_buffer[y * DOOM_WIDTH + x];
-                let color = Color::new(((pixel >> 16) & 0xFF) as u8, ((pixel >> 8) & 0xFF) as u8, (pixel & 0xFF) as u8);
-                frame.set(j, i, color);
-            }
-        }
-        self.updater_tx.blocking_send(UpdaterMessage::Frame(frame)).unwrap_or_else(|_| quit_upon_channel_close());
+        self.updater_tx.blocking_send(UpdaterMessage::Frame(frame.clone())).unwrap_or_else(|_| quit_upon_channel_close());
+
+        // Send frame to the other outputs, e.g. the recorder, which the game
+        // goes on without if they fail (they log their errors themselves)
+        self.output_txs.retain(|output_tx| output_tx.blocking_send(UpdaterMessage::Frame(frame.clone())).is_ok());
     }
 
     fn get_key(&mut self) -> Option<KeyData> {
-        self.mapper_tx.try_recv().ok().and_then(|message| match message {
-            MapperMessage::Action { action, down } => {
-                convert_action(action).map(|code| {
-                    let key_data = KeyData { pressed: down, key: code };
-                    info!("{:?}", key_data);
-                    key_data
-                })
+        if let Some(key_data) = self.kiosk.take_key() {
+            return Some(key_data);
+        }
+        while let Ok(message) = self.mapper_tx.try_recv() {
+            match message {
+                MapperMessage::Action { action, down } => {
+                    // The first key press only ends the attract mode
+                    if down && self.attract.handle_input() {
+                        continue;
+                    }
+                    if let Some(code) = convert_action(action) {
+                        let key_data = KeyData { pressed: down, key: code };
+                        if !self.kiosk.filter(&key_data) {
+                            info!("Blocked {:?} in kiosk mode", key_data);
+                            continue;
+                        }
+                        info!("{:?}", key_data);
+                        return Some(key_data);
+                    }
+                },
+                MapperMessage::Command(command) => self.run_command(command),
             }
-        })
+        }
+        None
     }
 
     fn set_window_title(&mut self, title: &str) {
@@ -95,6 +150,42 @@ impl DoomGeneric for LighthouseDoom {
     }
 }
 
+impl LighthouseDoom {
+    fn run_command(&mut self, command: Command) {
+        match command {
+            Command::Screenshot => {
+                let Some(frame) = self.frame_pool.latest() else {
+                    warn!("Cannot take a screenshot before the first frame");
+                    return;
+                };
+                // Don't stall the game if the screenshots can't keep up
+                match self.screenshot_tx.try_send(ScreenshotMessage::Capture(frame)) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full(_)) => warn!("Dropping screenshot, still busy saving the previous ones"),
+                    Err(TrySendError::Closed(_)) => quit_upon_channel_close(),
+                }
+            },
+            Command::RecordDemo => {
+                let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S");
+                let path = self.demo_options.demo_dir.join(format!("lidoom_{timestamp}.lmp"));
+                let result = fs::create_dir_all(&self.demo_options.demo_dir).map_err(Into::into)
+                    .and_then(|()| demo::start_recording(&path));
+                match result {
+                    Ok(()) => info!("Recording demo to {}", path.display()),
+                    Err(e) => warn!("Could not start recording a demo: {e:#}"),
+                }
+            },
+            Command::StopDemo => match demo::stop_recording() {
+                Ok(path) => info!("Saved demo to {}", path.display()),
+                Err(e) => warn!("Could not stop recording a demo: {e:#}"),
+            },
+            Command::Attract => if let Err(e) = self.attract.start() {
+                warn!("Could not enter attract mode: {e:#}");
+            },
+        }
+    }
+}
+
 fn quit_upon_channel_close() {
     // When one of the channels close, this means one of the other threads
     // (Tokio or the GUI/main thread) have finished, indicating that the
@@ -102,9 +193,28 @@ fn quit_upon_channel_close() {
     // C functions, we'll just exit the process from here to avoid a
     // crash message.
     info!("Quitting upong channel close...");
+    if demo::is_recording() {
+        match demo::stop_recording() {
+            Ok(path) => info!("Saved demo to {}", path.display()),
+            Err(e) => warn!("Could not save demo: {e:#}"),
+        }
+    }
     process::exit(0);
 }
 
+/// Passes command-line arguments to DOOM, which parses them during init.
+fn set_args(args: Vec<OsString>) {
+    // DOOM keeps referring to the arguments, so we leak them
+    let argv: Vec<*mut c_char> = iter::once(OsString::from("lidoom")).chain(args)
+        .map(|arg| CString::new(arg.into_encoded_bytes()).expect("Arguments cannot contain nul bytes").into_raw())
+        .chain(iter::once(ptr::null_mut()))
+        .collect();
+    unsafe {
+        game::myargc = (argv.len() - 1) as c_int;
+        game::myargv = argv.leak().as_mut_ptr();
+    }
+}
+
 fn convert_action(action: Action) -> Option<u8> {
     match action {
         Action::Right => Some(*KEY_RIGHT),
@@ -116,8 +226,28 @@ fn convert_action(action: Action) -> Option<u8> {
         Action::Use => Some(*KEY_USE),
         Action::Fire => Some(*KEY_FIRE),
         Action::Speed => Some(*KEY_SPEED),
+        Action::Strafe => Some(*KEY_STRAFE),
         Action::Escape => Some(KEY_ESCAPE),
         Action::Enter => Some(KEY_ENTER),
-        Action::KeyLetter(c) => keys::from_char(c.to_ascii_lowercase()), // TODO: Is this correct?
+        // The remaining codes are not exposed by doomgeneric, see doomkeys.h
+        Action::Tab => Some(9),
+        Action::Backspace => Some(0x7f),
+        Action::Pause => Some(0xff),
+        Action::CapsLock => Some(0x80 + 0x3a),
+        Action::NumLock => Some(0x80 + 0x45),
+        Action::ScrollLock => Some(0x80 + 0x46),
+        Action::PrintScreen => Some(0x80 + 0x59),
+        Action::Home => Some(0x80 + 0x47),
+        Action::End => Some(0x80 + 0x4f),
+        Action::PageUp => Some(0x80 + 0x49),
+        Action::PageDown => Some(0x80 + 0x51),
+        Action::Insert => Some(0x80 + 0x52),
+        Action::Delete => Some(0x80 + 0x53),
+        Action::Function(n @ 1..=10) => Some(0x80 + 0x3a + n),
+        Action::Function(11) => Some(0x80 + 0x57),
+        Action::Function(12) => Some(0x80 + 0x58),
+        Action::Function(_) => None,
+        // DOOM expects lowercase ASCII for printable keys
+        Action::KeyLetter(c) => keys::from_char(c.to_ascii_lowercase()),
     }
 }
@@ -0,0 +1,341 @@
+//! Exports of the session as animated GIFs or APNGs, either live or from a
+//! frame recording.
+//!
+//! Since lidoom usually exits abruptly, the writers keep the file valid after
+//! every frame, i.e. it can be viewed even if the export never finishes.
+
+use std::{fs::File, future::Future, io::{BufWriter, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::mpsc as std_mpsc, time::Duration};
+
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+use flate2::{write::ZlibEncoder, Compression};
+use lighthouse_client::protocol::{LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+use tokio::{sync::mpsc, task, time::Instant};
+use tracing::info;
+
+use crate::{constants::{DOOM_HEIGHT, DOOM_WIDTH}, facade::FacadeSettings, framebuffer::{FrameBuffer, FramePool, SharedFrame}, message::UpdaterMessage, recording};
+
+/// The byte ending a GIF.
+const GIF_TRAILER: u8 = 0x3b;
+/// How fast to quantize GIF frames, from 1 (best quality) to 30 (fastest).
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+/// The offset of the `acTL` chunk, right after the signature and `IHDR`.
+const APNG_ACTL_OFFSET: u64 = 8 + 12 + 13;
+/// The size of the `IEND` chunk.
+const APNG_IEND_SIZE: i64 = 12;
+
+/// Which image is exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportSource {
+    /// The 28x14 frame displayed on the lighthouse.
+    Lighthouse,
+    /// The frame displayed on the lighthouse, rendered as the facade (see --facade).
+    Facade,
+    /// The full DOOM image.
+    Doom,
+}
+
+impl ExportSource {
+    fn size(self, facade: &FacadeSettings) -> (usize, usize) {
+        match self {
+            Self::Lighthouse => (LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS),
+            Self::Facade => facade.size(),
+            Self::Doom => (DOOM_WIDTH, DOOM_HEIGHT),
+        }
+    }
+
+    fn render(self, frame: &FrameBuffer, facade: &FacadeSettings) -> Vec<u8> {
+        match self {
+            Self::Lighthouse => Vec::from(*frame.lighthouse()),
+            Self::Facade => facade.render(frame.lighthouse()),
+            Self::Doom => frame.rgb(),
+        }
+    }
+}
+
+/// Settings for exporting the session live.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Recording")]
+pub struct ExportOptions {
+    /// An animated GIF (.gif) or APNG (.png or .apng) to export the session to.
+    #[arg(long, env = "LIDOOM_EXPORT")]
+    pub export: Option<PathBuf>,
+    /// Which image to export.
+    #[arg(long, env = "LIDOOM_EXPORT_SOURCE", value_enum, default_value_t = ExportSource::Facade)]
+    pub export_source: ExportSource,
+    /// The factor by which to upscale the exported image.
+    #[arg(long, env = "LIDOOM_EXPORT_SCALE", default_value_t = 1)]
+    pub export_scale: usize,
+}
+
+/// Settings for exporting a frame recording.
+#[derive(Debug, Clone, Args)]
+pub struct ExportCommandOptions {
+    /// The recording to export, as created with --record-frames.
+    pub recording: PathBuf,
+    /// The animated GIF (.gif) or APNG (.png or .apng) to write.
+    pub output: PathBuf,
+    /// Which image to export. Since recordings only contain the lighthouse
+    /// frames, the DOOM image is upscaled from them.
+    #[arg(long, value_enum, default_value_t = ExportSource::Facade)]
+    pub source: ExportSource,
+    /// The factor by which to upscale the exported image.
+    #[arg(long, default_value_t = 1)]
+    pub scale: usize,
+}
+
+/// Renders frames from a source and writes them to an animation.
+struct Exporter {
+    writer: AnimationWriter,
+    source: ExportSource,
+    scale: usize,
+    facade: FacadeSettings,
+}
+
+impl Exporter {
+    fn create(path: &Path, source: ExportSource, scale: usize, facade: FacadeSettings) -> Result<Self> {
+        if scale == 0 {
+            bail!("Scale must be positive");
+        }
+        let (width, height) = source.size(&facade);
+        let writer = AnimationWriter::create(path, scale * width, scale * height)?;
+        Ok(Self { writer, source, scale, facade })
+    }
+
+    /// Appends a frame that is shown for the given duration.
+    fn write(&mut self, frame: &FrameBuffer, duration: Duration) -> Result<()> {
+        let (width, height) = self.source.size(&self.facade);
+        let rgb = upscale(&self.source.render(frame, &self.facade), width, height, self.scale);
+        self.writer.write(&rgb, duration)
+    }
+}
+
+/// An animated image that frames can be appended to.
+enum AnimationWriter {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        width: u16,
+        height: u16,
+        /// The exact and the written (rounded) duration so far, used to avoid
+        /// drifting when rounding to centiseconds.
+        elapsed: Duration,
+        elapsed_cs: u64,
+    },
+    Apng(ApngWriter),
+}
+
+impl AnimationWriter {
+    fn create(path: &Path, width: usize, height: usize) -> Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("gif") => {
+                let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+                    bail!("{width}x{height} is too large for a GIF");
+                };
+                let mut encoder = gif::Encoder::new(file, width, height, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Ok(Self::Gif { encoder, width, height, elapsed: Duration::ZERO, elapsed_cs: 0 })
+            },
+            Some("png" | "apng") => Ok(Self::Apng(ApngWriter::new(file, width as u32, height as u32)?)),
+            _ => bail!("Unsupported export format, use .gif, .png or .apng: {}", path.display()),
+        }
+    }
+
+    /// Appends RGB24 pixels that are shown for the given duration.
+    fn write(&mut self, rgb: &[u8], duration: Duration) -> Result<()> {
+        match self {
+            Self::Gif { encoder, width, height, elapsed, elapsed_cs } => {
+                *elapsed += duration;
+                let target_cs = (elapsed.as_secs_f64() * 100.0).round() as u64;
+                let delay = target_cs.saturating_sub(*elapsed_cs).min(u16::MAX as u64) as u16;
+                *elapsed_cs += delay as u64;
+
+                let mut frame = gif::Frame::from_rgb_speed(*width, *height, rgb, GIF_QUANTIZATION_SPEED);
+                frame.delay = delay;
+                encoder.write_frame(&frame)?;
+
+                // Terminate the file, the next frame will overwrite the trailer again
+                let file = encoder.get_mut();
+                file.write_all(&[GIF_TRAILER])?;
+                file.flush()?;
+                file.seek(SeekFrom::Current(-1))?;
+            },
+            Self::Apng(writer) => writer.write(rgb, duration)?,
+        }
+        Ok(())
+    }
+}
+
+/// A minimal APNG writer that patches the frame count after every frame.
+struct ApngWriter {
+    file: BufWriter<File>,
+    width: u32,
+    height: u32,
+    frames: u32,
+    sequence: u32,
+}
+
+impl ApngWriter {
+    fn new(file: BufWriter<File>, width: u32, height: u32) -> Result<Self> {
+        let mut writer = Self { file, width, height, frames: 0, sequence: 0 };
+        writer.file.write_all(PNG_SIGNATURE)?;
+        let mut ihdr = Vec::new();
+        ihdr.extend(width.to_be_bytes());
+        ihdr.extend(height.to_be_bytes());
+        // 8-bit RGB, default compression and filtering, no interlacing
+        ihdr.extend([8, 2, 0, 0, 0]);
+        writer.write_chunk(b"IHDR", &ihdr)?;
+        writer.write_actl()?;
+        writer.finish()?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, rgb: &[u8], duration: Duration) -> Result<()> {
+        let delay_ms = u16::try_from(duration.as_millis()).unwrap_or(u16::MAX);
+        let mut fctl = Vec::new();
+        fctl.extend(self.next_sequence().to_be_bytes());
+        fctl.extend(self.width.to_be_bytes());
+        fctl.extend(self.height.to_be_bytes());
+        fctl.extend([0u8; 8]); // x and y offset
+        fctl.extend(delay_ms.to_be_bytes());
+        fctl.extend(1000u16.to_be_bytes());
+        fctl.extend([0, 0]); // no disposal, no blending
+        self.write_chunk(b"fcTL", &fctl)?;
+
+        // Every scanline is prefixed with its filter type (none)
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        for row in rgb.chunks_exact(3 * self.width as usize) {
+            encoder.write_all(&[0])?;
+            encoder.write_all(row)?;
+        }
+        let data = encoder.finish()?;
+        if self.frames == 0 {
+            self.write_chunk(b"IDAT", &data)?;
+        } else {
+            let mut fdat = self.next_sequence().to_be_bytes().to_vec();
+            fdat.extend(data);
+            self.write_chunk(b"fdAT", &fdat)?;
+        }
+        self.frames += 1;
+
+        self.finish()?;
+        self.file.seek(SeekFrom::Start(APNG_ACTL_OFFSET))?;
+        self.write_actl()?;
+        self.file.seek(SeekFrom::End(-APNG_IEND_SIZE))?;
+        Ok(())
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        sequence
+    }
+
+    fn write_actl(&mut self) -> Result<()> {
+        let mut actl = self.frames.to_be_bytes().to_vec();
+        actl.extend(0u32.to_be_bytes()); // loop forever
+        self.write_chunk(b"acTL", &actl)
+    }
+
+    /// Terminates the file, the next frame will overwrite the `IEND` again.
+    fn finish(&mut self) -> Result<()> {
+        self.write_chunk(b"IEND", &[])?;
+        self.file.flush()?;
+        self.file.seek(SeekFrom::End(-APNG_IEND_SIZE))?;
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk_type: &[u8; 4], data: &[u8]) -> Result<()> {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(chunk_type);
+        crc.update(data);
+        self.file.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.file.write_all(chunk_type)?;
+        self.file.write_all(data)?;
+        self.file.write_all(&crc.finalize().to_be_bytes())?;
+        Ok(())
+    }
+}
+
+/// Upscales RGB24 pixels by the given integer factor.
+fn upscale(rgb: &[u8], width: usize, height: usize, scale: usize) -> Vec<u8> {
+    if scale == 1 {
+        return rgb.to_vec();
+    }
+    let mut scaled = Vec::with_capacity(3 * width * height * scale * scale);
+    for row in rgb.chunks_exact(3 * width).take(height) {
+        let scaled_row: Vec<u8> = row.chunks_exact(3)
+            .flat_map(|pixel| pixel.iter().copied().cycle().take(3 * scale))
+            .collect();
+        for _ in 0..scale {
+            scaled.extend(&scaled_row);
+        }
+    }
+    scaled
+}
+
+/// Creates the export, returning the task that exports the frames to it.
+pub fn start(
+    rx: mpsc::Receiver<UpdaterMessage>,
+    options: ExportOptions,
+    path: PathBuf,
+    facade: FacadeSettings,
+) -> Result<impl Future<Output = Result<()>>> {
+    let exporter = Exporter::create(&path, options.export_source, options.export_scale, facade)?;
+    info!("Exporting to {}", path.display());
+    Ok(run(rx, exporter))
+}
+
+async fn run(mut rx: mpsc::Receiver<UpdaterMessage>, mut exporter: Exporter) -> Result<()> {
+    // Encoding (especially quantizing GIFs) may be slower than DOOM renders, so
+    // we encode on a separate thread and skip frames while it is busy
+    let (frame_tx, frame_rx) = std_mpsc::sync_channel::<(Instant, SharedFrame)>(1);
+    let encoder_handle = task::spawn_blocking(move || {
+        // A frame is written once we know how long it is shown
+        let mut pending: Option<(Instant, SharedFrame)> = None;
+        let mut last_duration: Option<Duration> = None;
+        for (time, frame) in frame_rx {
+            if let Some((pending_time, pending_frame)) = pending.replace((time, frame)) {
+                let duration = time - pending_time;
+                exporter.write(&pending_frame, duration)?;
+                last_duration = Some(duration);
+            }
+        }
+        // Nothing follows the last frame, so we'll show it as long as the one
+        // before (or as long as it was shown, if it is the only one)
+        if let Some((pending_time, pending_frame)) = pending {
+            exporter.write(&pending_frame, last_duration.unwrap_or_else(|| pending_time.elapsed()))?;
+        }
+        anyhow::Ok(())
+    });
+
+    while let Some(UpdaterMessage::Frame(frame)) = rx.recv().await {
+        if let Err(std_mpsc::TrySendError::Disconnected(_)) = frame_tx.try_send((Instant::now(), frame)) {
+            // The encoder failed, its error is reported below
+            break;
+        }
+    }
+
+    drop(frame_tx);
+    encoder_handle.await?
+}
+
+/// Exports a frame recording.
+pub fn export_recording(options: ExportCommandOptions, facade: FacadeSettings) -> Result<()> {
+    let frames = recording::read_all(&options.recording)?;
+    let mut exporter = Exporter::create(&options.output, options.source, options.scale, facade)?;
+    let mut pool = FramePool::new();
+
+    for (i, (delay, frame)) in frames.iter().enumerate() {
+        // Recordings store the time since the previous frame, whereas we need
+        // the time until the next one (which we'll estimate for the last frame)
+        let duration = frames.get(i + 1).map(|(next_delay, _)| *next_delay).unwrap_or(*delay);
+        exporter.write(&pool.write_lighthouse(frame), duration)?;
+    }
+
+    info!("Exported {} frames to {}", frames.len(), options.output.display());
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error};
+use lighthouse_client::protocol::{Frame, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+
+/// The color of the facade between the windows.
+const WALL_COLOR: [u8; 3] = [28, 28, 30];
+
+/// Settings for rendering a `Frame` the way it looks on the building, i.e. as
+/// a grid of windows separated by wall.
+///
+/// Parsed from a comma-separated list of `key=value` pairs, e.g.
+/// `window=8x10,gap=4x10,glow=0.5`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FacadeSettings {
+    /// The size of a single window in pixels.
+    pub window_size: (usize, usize),
+    /// The horizontal gap between windows and the vertical gap between floors.
+    pub gap: (usize, usize),
+    /// How strongly the windows light up the surrounding wall, from 0 to 1.
+    pub glow: f64,
+}
+
+impl FacadeSettings {
+    /// The size of the rendered facade in pixels.
+    pub fn size(&self) -> (usize, usize) {
+        (
+            LIGHTHOUSE_COLS * (self.window_size.0 + self.gap.0) + self.gap.0,
+            LIGHTHOUSE_ROWS * (self.window_size.1 + self.gap.1) + self.gap.1,
+        )
+    }
+
+    /// Renders the given frame to RGB24 pixels in row-major order, whose
+    /// dimensions are given by `size`.
+    pub fn render(&self, frame: &Frame) -> Vec<u8> {
+        let (width, height) = self.size();
+        let (window_width, window_height) = self.window_size;
+        let (gap_x, gap_y) = self.gap;
+
+        // Draw the lit windows onto a black layer first, so we can derive the glow from it
+        let mut windows = vec![[0f32; 3]; width * height];
+        let mut is_window = vec![false; width * height];
+        for row in 0..LIGHTHOUSE_ROWS {
+            for col in 0..LIGHTHOUSE_COLS {
+                let color = frame.get(col, row);
+                let color = [color.red, color.green, color.blue].map(|c| c as f32);
+                let x0 = gap_x + col * (window_width + gap_x);
+                let y0 = gap_y + row * (window_height + gap_y);
+                for y in y0..(y0 + window_height) {
+                    for x in x0..(x0 + window_width) {
+                        windows[y * width + x] = color;
+                        is_window[y * width + x] = true;
+                    }
+                }
+            }
+        }
+
+        let glow = if self.glow > 0.0 {
+            let radius = gap_x.max(gap_y).max(1);
+            box_blur(&box_blur(&windows, width, height, radius, true), width, height, radius, false)
+        } else {
+            Vec::new()
+        };
+
+        let mut pixels = vec![0u8; 3 * width * height];
+        for i in 0..(width * height) {
+            let color = if is_window[i] {
+                windows[i]
+            } else if glow.is_empty() {
+                WALL_COLOR.map(|c| c as f32)
+            } else {
+                let mut color = WALL_COLOR.map(|c| c as f32);
+                for (c, g) in color.iter_mut().zip(glow[i]) {
+                    *c += g * self.glow as f32;
+                }
+                color
+            };
+            for (channel, value) in pixels[(3 * i)..(3 * i + 3)].iter_mut().zip(color) {
+                *channel = value.clamp(0.0, 255.0) as u8;
+            }
+        }
+        pixels
+    }
+}
+
+impl Default for FacadeSettings {
+    fn default() -> Self {
+        Self {
+            window_size: (8, 10),
+            gap: (4, 10),
+            glow: 0.0,
+        }
+    }
+}
+
+impl FromStr for FacadeSettings {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_size = |value: &str| -> Result<(usize, usize), Error> {
+            let (width, height) = value.split_once('x').ok_or_else(|| anyhow!("Expected a size of the form WxH, but got '{value}'"))?;
+            Ok((
+                width.parse().with_context(|| format!("Invalid width '{width}'"))?,
+                height.parse().with_context(|| format!("Invalid height '{height}'"))?,
+            ))
+        };
+
+        let mut settings = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| anyhow!("Expected key=value, but got '{pair}'"))?;
+            match key {
+                "window" => settings.window_size = parse_size(value)?,
+                "gap" => settings.gap = parse_size(value)?,
+                "glow" => settings.glow = value.parse().with_context(|| format!("Invalid glow '{value}'"))?,
+                _ => bail!("Unknown facade setting '{key}'"),
+            }
+        }
+        if settings.window_size.0 == 0 || settings.window_size.1 == 0 {
+            bail!("Windows must not be empty");
+        }
+        Ok(settings)
+    }
+}
+
+/// Blurs the given image horizontally or vertically with a box of the given
+/// radius.
+fn box_blur(pixels: &[[f32; 3]], width: usize, height: usize, radius: usize, horizontal: bool) -> Vec<[f32; 3]> {
+    let (lines, length) = if horizontal { (height, width) } else { (width, height) };
+    let index = |line: usize, i: usize| if horizontal { line * width + i } else { i * width + line };
+    let diameter = (2 * radius + 1) as f32;
+
+    let mut blurred = vec![[0f32; 3]; pixels.len()];
+    for line in 0..lines {
+        // Slide a window of the given radius along the line, keeping a running sum
+        let mut sum = [0f32; 3];
+        for i in 0..radius.min(length) {
+            for (s, p) in sum.iter_mut().zip(pixels[index(line, i)]) {
+                *s += p;
+            }
+        }
+        for i in 0..length {
+            if i + radius < length {
+                for (s, p) in sum.iter_mut().zip(pixels[index(line, i + radius)]) {
+                    *s += p;
+                }
+            }
+            if i > radius {
+                for (s, p) in sum.iter_mut().zip(pixels[index(line, i - radius - 1)]) {
+                    *s -= p;
+                }
+            }
+            blurred[index(line, i)] = sum.map(|s| s / diameter);
+        }
+    }
+    blurred
+}
+
+#[cfg(test)]
+mod tests {
+    use lighthouse_client::protocol::{Color, Frame, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+
+    use super::{box_blur, FacadeSettings, WALL_COLOR};
+
+    #[test]
+    fn blur_matches_naive() {
+        let (width, height) = (7, 5);
+        let pixels: Vec<[f32; 3]> = (0..(width * height)).map(|i| [i as f32, (i % 3) as f32, 0.0]).collect();
+        for radius in [1, 2, 3, 10] {
+            for horizontal in [true, false] {
+                let blurred = box_blur(&pixels, width, height, radius, horizontal);
+                for y in 0..height {
+                    for x in 0..width {
+                        // Pixels outside of the image count as black
+                        let mut sum = [0f32; 3];
+                        for d in -(radius as isize)..=(radius as isize) {
+                            let (nx, ny) = if horizontal { (x as isize + d, y as isize) } else { (x as isize, y as isize + d) };
+                            if (0..width as isize).contains(&nx) && (0..height as isize).contains(&ny) {
+                                for (s, p) in sum.iter_mut().zip(pixels[ny as usize * width + nx as usize]) {
+                                    *s += p;
+                                }
+                            }
+                        }
+                        let expected = sum.map(|s| s / (2 * radius + 1) as f32);
+                        for (b, e) in blurred[y * width + x].iter().zip(expected) {
+                            assert!((b - e).abs() < 1e-3, "radius {radius}, horizontal {horizontal}: {b} at ({x}, {y}) should be close to {e}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn render() {
+        let settings = FacadeSettings { window_size: (2, 3), gap: (1, 2), glow: 0.0 };
+        let (width, height) = settings.size();
+        assert_eq!((width, height), (LIGHTHOUSE_COLS * 3 + 1, LIGHTHOUSE_ROWS * 5 + 2));
+
+        let mut frame = Frame::empty();
+        frame.set(1, 0, Color::new(10, 20, 30));
+        let pixels = settings.render(&frame);
+        assert_eq!(pixels.len(), 3 * width * height);
+        let pixel = |x: usize, y: usize| &pixels[(3 * (y * width + x))..(3 * (y * width + x) + 3)];
+        assert_eq!(pixel(0, 0), WALL_COLOR);
+        assert_eq!(pixel(1, 2), [0, 0, 0]);
+        assert_eq!(pixel(4, 2), [10, 20, 30]);
+        assert_eq!(pixel(5, 4), [10, 20, 30]);
+        assert_eq!(pixel(6, 2), WALL_COLOR);
+    }
+
+    #[test]
+    fn glow() {
+        let settings = FacadeSettings { window_size: (2, 3), gap: (1, 2), glow: 1.0 };
+        let mut frame = Frame::empty();
+        frame.set(1, 0, Color::new(255, 255, 255));
+        let pixels = settings.render(&frame);
+        let width = settings.size().0;
+        let pixel = |x: usize, y: usize| &pixels[(3 * (y * width + x))..(3 * (y * width + x) + 3)];
+        // The wall next to the lit window is brighter, the wall far away is not
+        assert!(pixel(6, 2)[0] > WALL_COLOR[0]);
+        assert_eq!(pixel(width - 1, 0), WALL_COLOR);
+    }
+
+    #[test]
+    fn settings() {
+        assert_eq!("".parse::<FacadeSettings>().unwrap(), FacadeSettings::default());
+        assert_eq!(
+            "window=3x4, gap=0x1,glow=0.5".parse::<FacadeSettings>().unwrap(),
+            FacadeSettings { window_size: (3, 4), gap: (0, 1), glow: 0.5 },
+        );
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for s in ["window", "window=3", "window=0x4", "gap=ax1", "glow=bright", "shine=1"] {
+            assert!(s.parse::<FacadeSettings>().is_err(), "{s} should be invalid");
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{mem, slice, sync::Arc};
+
+use lighthouse_client::protocol::{Color, Frame, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+use tracing::debug;
+
+use crate::{constants::{DOOM_HEIGHT, DOOM_WIDTH}, overlay::Overlay};
+
+/// The number of buffers that are allocated upfront. Three buffers let DOOM
+/// render the next frame while the outputs still read the current and the
+/// previous one.
+const INITIAL_BUFFERS: usize = 3;
+
+/// A frame rendered by DOOM, along with the downsampled frame for the
+/// lighthouse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameBuffer {
+    /// The pixels in DOOM's native XRGB8888 format, in row-major order.
+    pixels: Vec<u32>,
+    /// The frame to display on the lighthouse.
+    lighthouse: Frame,
+}
+
+/// A frame that is shared between DOOM and the outputs without copying.
+pub type SharedFrame = Arc<FrameBuffer>;
+
+impl FrameBuffer {
+    fn new() -> Self {
+        Self {
+            pixels: vec![0; DOOM_WIDTH * DOOM_HEIGHT],
+            lighthouse: Frame::empty(),
+        }
+    }
+
+    /// The pixels converted to RGB24.
+    pub fn rgb(&self) -> Vec<u8> {
+        self.pixels.iter()
+            .flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])
+            .collect()
+    }
+
+    /// The pixels as native-endian bytes, e.g. for uploading them to a
+    /// texture.
+    pub fn pixel_bytes(&self) -> &[u8] {
+        // SAFETY: Every byte of a u32 is initialized and u8 has no alignment
+        // requirements, so viewing the pixels as bytes is sound.
+        unsafe { slice::from_raw_parts(self.pixels.as_ptr().cast(), self.pixels.len() * mem::size_of::<u32>()) }
+    }
+
+    /// The frame to display on the lighthouse.
+    pub fn lighthouse(&self) -> &Frame {
+        &self.lighthouse
+    }
+
+    fn write(&mut self, screen_buffer: &[u32], overlay: Option<&Overlay>) {
+        self.pixels.copy_from_slice(screen_buffer);
+
+        // Downsample frame for the lighthouse
+        for i in 0..LIGHTHOUSE_ROWS {
+            for j in 0..LIGHTHOUSE_COLS {
+                let y = (i * DOOM_HEIGHT) / LIGHTHOUSE_ROWS;
+                let x = (j * DOOM_WIDTH) / LIGHTHOUSE_COLS;
+                let pixel = screen_buffer[y * DOOM_WIDTH + x];
+                let color = Color::new(((pixel >> 16) & 0xFF) as u8, ((pixel >> 8) & 0xFF) as u8, (pixel & 0xFF) as u8);
+                self.lighthouse.set(j, i, color);
+            }
+        }
+
+        if let Some(overlay) = overlay {
+            overlay.draw(&mut self.pixels, &mut self.lighthouse);
+        }
+    }
+
+    fn write_lighthouse(&mut self, frame: &Frame) {
+        self.lighthouse = *frame;
+
+        // Upscale frame for outputs that expect DOOM's resolution
+        for y in 0..DOOM_HEIGHT {
+            for x in 0..DOOM_WIDTH {
+                let color = frame.get(x * LIGHTHOUSE_COLS / DOOM_WIDTH, y * LIGHTHOUSE_ROWS / DOOM_HEIGHT);
+                self.pixels[y * DOOM_WIDTH + x] = ((color.red as u32) << 16) | ((color.green as u32) << 8) | color.blue as u32;
+            }
+        }
+    }
+}
+
+/// A pool of frame buffers that DOOM renders into. A buffer is reused once all
+/// outputs have dropped their references to it, so no allocations happen after
+/// startup unless the outputs fall behind.
+#[derive(Debug)]
+pub struct FramePool {
+    buffers: Vec<SharedFrame>,
+    latest: Option<usize>,
+}
+
+impl FramePool {
+    pub fn new() -> Self {
+        Self {
+            buffers: (0..INITIAL_BUFFERS).map(|_| Arc::new(FrameBuffer::new())).collect(),
+            latest: None,
+        }
+    }
+
+    /// The most recently written buffer, if any.
+    pub fn latest(&self) -> Option<SharedFrame> {
+        self.latest.map(|index| self.buffers[index].clone())
+    }
+
+    /// Writes DOOM's screen buffer (with an optional overlay on top) to a
+    /// free buffer and returns it.
+    pub fn write(&mut self, screen_buffer: &[u32], overlay: Option<&Overlay>) -> SharedFrame {
+        self.write_with(|buffer| buffer.write(screen_buffer, overlay))
+    }
+
+    /// Writes a lighthouse frame (e.g. from a recording) to a free buffer and
+    /// returns it. The full-resolution image is upscaled from it.
+    pub fn write_lighthouse(&mut self, frame: &Frame) -> SharedFrame {
+        self.write_with(|buffer| buffer.write_lighthouse(frame))
+    }
+
+    fn write_with(&mut self, write: impl FnOnce(&mut FrameBuffer)) -> SharedFrame {
+        let index = match self.buffers.iter_mut().position(|buffer| Arc::get_mut(buffer).is_some()) {
+            Some(index) => index,
+            None => {
+                debug!("All {} frame buffers are in use, allocating another one", self.buffers.len());
+                self.buffers.push(Arc::new(FrameBuffer::new()));
+                self.buffers.len() - 1
+            },
+        };
+        let buffer = &mut self.buffers[index];
+        write(Arc::get_mut(buffer).expect("Frame buffer should be free"));
+        self.latest = Some(index);
+        buffer.clone()
+    }
+}
@@ -1,41 +1,268 @@
-use std::cell::Cell;
+use std::{cell::Cell, collections::HashMap, iter, sync::mpsc::{self as std_mpsc, TryRecvError}, thread};
 
 use anyhow::{anyhow, Result};
-use lighthouse_client::protocol::{Delta, Pos, Zero, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
-use sdl2::{event::Event, keyboard::Keycode, mouse::MouseButton as SDLMouseButton, pixels::PixelFormatEnum, render::Texture, surface::Surface};
+use clap::{Args, ValueEnum};
+use lighthouse_client::protocol::{Delta, Direction, Pos, Vec2, Zero, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+use sdl2::{controller::{Axis, Button, GameController}, event::{Event, WindowEvent}, keyboard::{Keycode, Mod, Scancode}, mouse::MouseButton as SDLMouseButton, pixels::{Color, PixelFormatEnum}, rect::Rect, render::{BlendMode, WindowCanvas}, video::{FullscreenType, Window}};
 use tokio::sync::mpsc;
-use tracing::info;
+use tracing::{debug, info};
 
-use crate::{constants::{DOOM_HEIGHT, DOOM_WIDTH}, message::{ControllerMessage, GUIMessage, Key, MouseButton}};
+use crate::{constants::{DOOM_HEIGHT, DOOM_WIDTH}, crowd, facade::FacadeSettings, message::{ControllerInput, ControllerMessage, GUIMessage, GamepadButton, GamepadStick, GamepadTrigger, InputSource, Key, MouseButton, VoteTally}, overlay::{self, GLYPH_ADVANCE, GLYPH_HEIGHT}};
+
+/// Settings for the SDL window.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "GUI")]
+pub struct GuiOptions {
+    /// The initial window size as a multiple of DOOM's resolution.
+    #[arg(long, env = "LIDOOM_WINDOW_SCALE", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub window_scale: u32,
+    /// How the DOOM image is scaled to the window.
+    #[arg(long, env = "LIDOOM_SCALING", value_enum, default_value_t = Scaling::Aspect)]
+    pub scaling: Scaling,
+    /// Whether to start in fullscreen (toggle with Alt+Enter or F11).
+    #[arg(long, env = "LIDOOM_FULLSCREEN")]
+    pub fullscreen: bool,
+    /// Whether to show a preview of the lighthouse facade (cycle with Alt+P).
+    #[arg(long, env = "LIDOOM_PREVIEW", value_enum, default_value_t = Preview::Off)]
+    pub preview: Preview,
+    /// Whether to synchronize presenting frames with the display's refresh rate.
+    #[arg(long, env = "LIDOOM_VSYNC")]
+    pub vsync: bool,
+}
+
+/// How the DOOM image is scaled to the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Scaling {
+    /// Scale by the largest integer factor that fits the window.
+    Integer,
+    /// Scale as large as possible while preserving the aspect ratio.
+    Aspect,
+    /// Fill the entire window.
+    Stretch,
+}
+
+/// How many received messages may be pending before the GUI applies
+/// backpressure.
+const MESSAGE_CAPACITY: usize = 8;
+
+/// The number of actions shown when visualizing the crowd's votes.
+const MAX_SHOWN_VOTES: usize = 5;
+/// The width of the bar of an action that got all the votes, in font pixels.
+const VOTE_BAR_WIDTH: usize = 40;
+const VOTE_WINNER_COLOR: Color = Color::RGB(255, 200, 0);
+const VOTE_COLOR: Color = Color::RGB(160, 160, 160);
+
+/// A custom SDL event that wakes up the event loop when a message arrives.
+struct Wakeup;
+
+/// Which of the DOOM image and the facade preview the window shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Preview {
+    /// Show only the DOOM image.
+    Off,
+    /// Show the facade preview next to the DOOM image.
+    SideBySide,
+    /// Show only the facade preview.
+    Only,
+}
+
+impl Preview {
+    fn next(self) -> Self {
+        match self {
+            Self::Off => Self::SideBySide,
+            Self::SideBySide => Self::Only,
+            Self::Only => Self::Off,
+        }
+    }
+}
+
+/// The arrangement of the DOOM image and the facade preview, in unscaled
+/// pixels.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    width: u32,
+    height: u32,
+    doom: Option<Rect>,
+    facade: Option<Rect>,
+}
+
+impl Layout {
+    fn new(preview: Preview, facade_settings: &FacadeSettings) -> Self {
+        let (facade_width, facade_height) = facade_settings.size();
+        let (doom_width, doom_height) = (DOOM_WIDTH as u32, DOOM_HEIGHT as u32);
+        match preview {
+            Preview::Off => Self {
+                width: doom_width,
+                height: doom_height,
+                doom: Some(Rect::new(0, 0, doom_width, doom_height)),
+                facade: None,
+            },
+            Preview::SideBySide => {
+                // Scale the facade to the height of the DOOM image
+                let facade_width = (facade_width as u32 * doom_height / facade_height as u32).max(1);
+                Self {
+                    width: doom_width + facade_width,
+                    height: doom_height,
+                    doom: Some(Rect::new(0, 0, doom_width, doom_height)),
+                    facade: Some(Rect::new(doom_width as i32, 0, facade_width, doom_height)),
+                }
+            },
+            Preview::Only => Self {
+                width: facade_width as u32,
+                height: facade_height as u32,
+                doom: None,
+                facade: Some(Rect::new(0, 0, facade_width as u32, facade_height as u32)),
+            },
+        }
+    }
+}
+
+/// Maps between window coordinates and the areas that the DOOM image and the
+/// facade preview are drawn to.
+#[derive(Debug, Clone, Copy)]
+struct Viewport {
+    /// The area the DOOM image is drawn to, in drawable pixels. If only the
+    /// facade is shown, this is the area of the facade, which shows the same
+    /// image after all.
+    rect: Rect,
+    /// The area the DOOM image is drawn to, if shown, in drawable pixels.
+    doom: Option<Rect>,
+    /// The area the facade preview is drawn to, if shown, in drawable pixels.
+    facade: Option<Rect>,
+    /// Drawable pixels per window coordinate, greater than 1 on HiDPI displays.
+    pixel_density: Vec2<f64>,
+}
+
+impl Viewport {
+    fn new(canvas: &WindowCanvas, scaling: Scaling, layout: Layout) -> Result<Self> {
+        let (window_width, window_height) = canvas.window().size();
+        let (width, height) = canvas.output_size().map_err(|e| anyhow!("{e}"))?;
+        let pixel_density = Vec2::new(
+            width as f64 / window_width.max(1) as f64,
+            height as f64 / window_height.max(1) as f64,
+        );
+
+        let scale = match scaling {
+            Scaling::Stretch => Vec2::new(width as f64 / layout.width as f64, height as f64 / layout.height as f64),
+            Scaling::Integer | Scaling::Aspect => {
+                let scale = f64::min(width as f64 / layout.width as f64, height as f64 / layout.height as f64);
+                // Integer scaling falls back to fractional scaling if the window is too small
+                let scale = if scaling == Scaling::Integer && scale >= 1.0 { scale.floor() } else { scale };
+                Vec2::new(scale, scale)
+            },
+        };
+        let offset = Vec2::new(
+            (width as f64 - layout.width as f64 * scale.x) / 2.0,
+            (height as f64 - layout.height as f64 * scale.y) / 2.0,
+        );
+        let scale_rect = |rect: Rect| Rect::new(
+            (offset.x + rect.x() as f64 * scale.x) as i32,
+            (offset.y + rect.y() as f64 * scale.y) as i32,
+            ((rect.width() as f64 * scale.x) as u32).max(1),
+            ((rect.height() as f64 * scale.y) as u32).max(1),
+        );
+
+        let doom = layout.doom.map(scale_rect);
+        let facade = layout.facade.map(scale_rect);
+        let rect = doom.or(facade).expect("Layout should show at least one of DOOM and the facade");
+
+        Ok(Self { rect, doom, facade, pixel_density })
+    }
+
+    /// Converts a position in window coordinates to DOOM pixels.
+    fn doom_pos(self, x: i32, y: i32) -> Pos<f64> {
+        Pos::new(
+            (x as f64 * self.pixel_density.x - self.rect.x() as f64) / self.rect.width() as f64 * DOOM_WIDTH as f64,
+            (y as f64 * self.pixel_density.y - self.rect.y() as f64) / self.rect.height() as f64 * DOOM_HEIGHT as f64,
+        )
+    }
+
+    /// Converts a distance in window coordinates to DOOM pixels.
+    fn doom_delta(self, dx: i32, dy: i32) -> Delta<f64> {
+        Delta::new(
+            dx as f64 * self.pixel_density.x / self.rect.width() as f64 * DOOM_WIDTH as f64,
+            dy as f64 * self.pixel_density.y / self.rect.height() as f64 * DOOM_HEIGHT as f64,
+        )
+    }
+}
 
 pub fn run(
     mut rx: mpsc::Receiver<GUIMessage>,
-    tx: mpsc::Sender<ControllerMessage>,
+    tx: mpsc::Sender<ControllerInput>,
+    options: GuiOptions,
+    facade: FacadeSettings,
 ) -> Result<()> {
     let sdl_context = sdl2::init().map_err(|e| anyhow!("{e}"))?;
     let video_subsystem = sdl_context.video().map_err(|e| anyhow!("{e}"))?;
+    let controller_subsystem = sdl_context.game_controller().map_err(|e| anyhow!("{e}"))?;
     
-    let window = video_subsystem
-        .window("DOOM", DOOM_WIDTH as u32, DOOM_HEIGHT as u32) // TODO: Receive titles
+    let mut preview = options.preview;
+    let initial_layout = Layout::new(preview, &facade);
+    let mut window_builder = video_subsystem.window("DOOM", options.window_scale * initial_layout.width, options.window_scale * initial_layout.height);
+    window_builder
         .position_centered()
-        .opengl()
-        .build()?;
+        .resizable()
+        .allow_highdpi()
+        .opengl();
+    if options.fullscreen {
+        window_builder.fullscreen_desktop();
+    }
+    let window = window_builder.build()?;
 
-    let mut canvas = window.into_canvas().build()?;
+    let mut canvas_builder = window.into_canvas();
+    if options.vsync {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build()?;
     canvas.clear();
     canvas.present();
 
+    // The textures are reused across frames and start out black
     let texture_creator = canvas.texture_creator();
+    let mut doom_texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB888, DOOM_WIDTH as u32, DOOM_HEIGHT as u32)?;
+    let (facade_width, facade_height) = facade.size();
+    let mut facade_texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, facade_width as u32, facade_height as u32)?;
+    for texture in [&mut doom_texture, &mut facade_texture] {
+        texture.with_lock(None, |pixels, _| pixels.fill(0)).map_err(|e| anyhow!("{e}"))?;
+    }
+    let mut needs_redraw = false;
+    let mut vote_tally: Option<VoteTally> = None;
+
+    // SDL can only wait for its own events, so we receive the messages on a
+    // separate thread that wakes up the event loop with a custom event
+    let event_subsystem = sdl_context.event().map_err(|e| anyhow!("{e}"))?;
+    event_subsystem.register_custom_event::<Wakeup>().map_err(|e| anyhow!("{e}"))?;
+    let event_sender = event_subsystem.event_sender();
+    let (message_tx, message_rx) = std_mpsc::sync_channel(MESSAGE_CAPACITY);
+    thread::Builder::new().name("GUI messages".into()).spawn(move || {
+        while let Some(message) = rx.blocking_recv() {
+            if message_tx.send(message).is_err() {
+                break;
+            }
+            _ = event_sender.push_custom_event(Wakeup);
+        }
+        // Wake up the event loop once more to let it notice the closed channel
+        drop(message_tx);
+        _ = event_sender.push_custom_event(Wakeup);
+    })?;
 
     let mut event_pump = sdl_context.event_pump().map_err(|e| anyhow!("{e}"))?;
     let mut last_pos: Option<Pos<f64>> = None;
 
     let mouse_down: Cell<bool> = Cell::new(false);
 
-    let mut handle_mouse_event = |sdl_button: Option<SDLMouseButton>, x: i32, y: i32, relative: bool, pointer_locked: bool| {
+    // Game controllers are opened as SDL reports them (including those that
+    // are already connected at startup), keyed by their instance id
+    let mut controllers: HashMap<u32, GameController> = HashMap::new();
+    let mut stick_values: HashMap<(u32, GamepadStick), Vec2<f64>> = HashMap::new();
+
+    let send = |message: ControllerMessage| tx.blocking_send(ControllerInput { source: InputSource::Gui, message });
+
+    // Takes positions (or deltas, if relative) in DOOM pixels
+    let mut handle_mouse_event = |sdl_button: Option<SDLMouseButton>, doom_pos: Pos<f64>, relative: bool, pointer_locked: bool| {
         let mut pos = Pos::new(
-            x as f64 / DOOM_WIDTH as f64 * LIGHTHOUSE_COLS as f64,
-            y as f64 / DOOM_HEIGHT as f64 * LIGHTHOUSE_ROWS as f64,
+            doom_pos.x / DOOM_WIDTH as f64 * LIGHTHOUSE_COLS as f64,
+            doom_pos.y / DOOM_HEIGHT as f64 * LIGHTHOUSE_ROWS as f64,
         );
         if relative {
             pos = last_pos.unwrap_or(Pos::ZERO) + pos;
@@ -43,13 +270,17 @@ pub fn run(
         let movement: Delta<f64> = pos - last_pos.unwrap_or(pos);
         last_pos = Some(pos);
         let button = sdl_button.and_then(convert_mouse_button).unwrap_or(MouseButton::Left);
-        tx.blocking_send(ControllerMessage::Mouse { button, movement, down: mouse_down.get(), pointer_locked })?;
+        send(ControllerMessage::Mouse { button, movement, down: mouse_down.get(), pointer_locked })?;
         anyhow::Ok(())
     };
 
     'running: loop {
-        if let Some(event) = event_pump.poll_event() {
+        // Block until there is anything to do, then handle everything that is pending
+        let first_event = event_pump.wait_event();
+        let mut events = iter::once(first_event).chain(event_pump.poll_iter()).peekable();
+        while let Some(event) = events.next() {
             let pointer_locked = canvas.window().grab();
+            let viewport = Viewport::new(&canvas, options.scaling, Layout::new(preview, &facade))?;
             match event {
                 Event::Quit { .. } => break 'running,
                 Event::MouseButtonDown { mouse_btn, x, y, .. } => {
@@ -61,59 +292,228 @@ pub fn run(
                     }
 
                     mouse_down.set(true);
-                    handle_mouse_event(Some(mouse_btn), x, y, false, pointer_locked)?;
+                    handle_mouse_event(Some(mouse_btn), viewport.doom_pos(x, y), false, pointer_locked)?;
                 },
                 Event::MouseButtonUp { mouse_btn, x, y, .. } => {
                     mouse_down.set(false);
-                    handle_mouse_event(Some(mouse_btn), x, y, false, pointer_locked)?;
+                    handle_mouse_event(Some(mouse_btn), viewport.doom_pos(x, y), false, pointer_locked)?;
                 },
-                Event::MouseMotion { xrel, yrel, .. } => {
-                    handle_mouse_event(None, xrel, yrel, true, pointer_locked)?;
+                Event::MouseMotion { mut xrel, mut yrel, .. } => {
+                    // Coalesce consecutive motion, otherwise we lag behind when the mouse floods events
+                    while let Some(Event::MouseMotion { xrel: next_xrel, yrel: next_yrel, .. }) = events.next_if(|e| matches!(e, Event::MouseMotion { .. })) {
+                        xrel += next_xrel;
+                        yrel += next_yrel;
+                    }
+                    handle_mouse_event(None, viewport.doom_delta(xrel, yrel), true, pointer_locked)?;
+                },
+                Event::KeyDown { scancode, keymod, .. } if is_fullscreen_toggle(scancode, keymod) => {
+                    toggle_fullscreen(canvas.window_mut())?;
+                },
+                Event::KeyUp { scancode, keymod, .. } if is_fullscreen_toggle(scancode, keymod) => {},
+                Event::KeyDown { scancode, keymod, .. } if is_preview_toggle(scancode, keymod) => {
+                    preview = preview.next();
+                    info!("Switching preview to {preview:?}");
+                    needs_redraw = true;
                 },
-                Event::KeyDown { keycode, .. } => {
+                Event::KeyUp { scancode, keymod, .. } if is_preview_toggle(scancode, keymod) => {},
+                Event::KeyDown { keycode, scancode, .. } => {
                     // Unlock pointer on escape
-                    if keycode == Some(Keycode::Escape) && pointer_locked {
+                    if scancode == Some(Scancode::Escape) && pointer_locked {
                         info!("Unlocking pointer");
                         canvas.window_mut().set_grab(false);
                         sdl_context.mouse().set_relative_mouse_mode(false);
+                        send(ControllerMessage::Release)?;
                     }
 
-                    if let Some(key) = convert_key(keycode) {
-                        tx.blocking_send(ControllerMessage::Key { key, down: true })?;
+                    if let Some(key) = convert_key(keycode, scancode) {
+                        send(ControllerMessage::Key { key, down: true })?;
+                    }
+                },
+                Event::KeyUp { keycode, scancode, .. } => {
+                    if let Some(key) = convert_key(keycode, scancode) {
+                        send(ControllerMessage::Key { key, down: false })?;
                     }
                 },
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = convert_key(keycode) {
-                        tx.blocking_send(ControllerMessage::Key { key, down: false })?;
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            info!("Connected game controller {}", controller.name());
+                            controllers.insert(controller.instance_id(), controller);
+                        },
+                        Err(e) => info!("Could not open game controller {which}: {e}"),
                     }
                 },
-                // TODO: Gamepad events
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(controller) = controllers.remove(&which) {
+                        info!("Disconnected game controller {}", controller.name());
+                    }
+                    // We won't see the button-ups of the removed controller, so release
+                    // everything (not only its sticks) to keep anything from getting stuck
+                    stick_values.retain(|&(id, _), _| id != which);
+                    send(ControllerMessage::Release)?;
+                },
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(button) = convert_controller_button(button) {
+                        send(ControllerMessage::GamepadButton { button, down: true })?;
+                    }
+                },
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(button) = convert_controller_button(button) {
+                        send(ControllerMessage::GamepadButton { button, down: false })?;
+                    }
+                },
+                Event::ControllerAxisMotion { which, axis, value, .. } => {
+                    let value = value as f64 / i16::MAX as f64;
+                    match axis {
+                        Axis::LeftX | Axis::LeftY | Axis::RightX | Axis::RightY => {
+                            let stick = match axis {
+                                Axis::LeftX | Axis::LeftY => GamepadStick::Left,
+                                _ => GamepadStick::Right,
+                            };
+                            let stick_value = stick_values.entry((which, stick)).or_insert(Vec2::ZERO);
+                            match axis {
+                                Axis::LeftX | Axis::RightX => stick_value.x = value,
+                                _ => stick_value.y = value,
+                            }
+                            send(ControllerMessage::GamepadStick { stick, value: *stick_value })?;
+                        },
+                        Axis::TriggerLeft => send(ControllerMessage::GamepadTrigger { trigger: GamepadTrigger::Left, value })?,
+                        Axis::TriggerRight => send(ControllerMessage::GamepadTrigger { trigger: GamepadTrigger::Right, value })?,
+                    }
+                },
+                Event::Window { win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed, .. } => {
+                    needs_redraw = true;
+                },
+                Event::Window { win_event: WindowEvent::FocusLost, .. } => {
+                    // We won't see the key-ups while another window has the focus
+                    send(ControllerMessage::Release)?;
+                },
+                _ if event.is_user_event() => {
+                    // Free the wakeup, the messages are handled below
+                    _ = event.as_user_event_type::<Wakeup>();
+                },
                 _ => {},
             }
         }
 
-        if let Ok(message) = rx.try_recv() {
-            match message {
-                GUIMessage::Frame(mut frame) => {
-                    let surface = Surface::from_data(
-                        &mut frame,
-                        DOOM_WIDTH as u32,
-                        DOOM_HEIGHT as u32,
-                        (DOOM_WIDTH * 3) as u32,
-                        PixelFormatEnum::RGB24
-                    ).map_err(|e| anyhow!("{e}"))?;
-
-                    let texture = Texture::from_surface(&surface, &texture_creator)?;
-                    canvas.copy(&texture, None, None).map_err(|e| anyhow!("{e}"))?;
-                    canvas.present();
-                },
-                GUIMessage::UpdateTitle(title) => {
+        // Only the latest frame is drawn, older ones are dropped if we fall behind
+        let mut latest_frame = None;
+        loop {
+            match message_rx.try_recv() {
+                Ok(GUIMessage::Frame(frame)) => latest_frame = Some(frame),
+                Ok(GUIMessage::UpdateTitle(title)) => {
                     canvas.window_mut().set_title(&title)?;
                 },
+                Ok(GUIMessage::Rumble { strength, duration_ms }) => {
+                    for controller in controllers.values_mut() {
+                        if let Err(e) = controller.set_rumble(strength, strength, duration_ms) {
+                            debug!("Could not rumble game controller {}: {e}", controller.name());
+                        }
+                    }
+                },
+                Ok(GUIMessage::Vote(tally)) => {
+                    vote_tally = Some(tally);
+                    needs_redraw = true;
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'running,
+            }
+        }
+
+        if let Some(frame) = latest_frame {
+            // DOOM's XRGB8888 pixels are uploaded as-is
+            doom_texture.update(None, frame.pixel_bytes(), 4 * DOOM_WIDTH)?;
+            // Only render the facade if needed
+            if preview != Preview::Off {
+                let pixels = facade.render(frame.lighthouse());
+                facade_texture.update(None, &pixels, 3 * facade_width)?;
             }
+            needs_redraw = true;
         }
+
+        if needs_redraw {
+            let viewport = Viewport::new(&canvas, options.scaling, Layout::new(preview, &facade))?;
+            canvas.set_draw_color(Color::BLACK);
+            canvas.clear();
+            if let Some(rect) = viewport.doom {
+                canvas.copy(&doom_texture, None, rect).map_err(|e| anyhow!("{e}"))?;
+            }
+            if let Some(rect) = viewport.facade {
+                canvas.copy(&facade_texture, None, rect).map_err(|e| anyhow!("{e}"))?;
+            }
+            if let Some(tally) = &vote_tally {
+                draw_votes(&mut canvas, tally, viewport.rect)?;
+            }
+            canvas.present();
+            needs_redraw = false;
+        }
+    }
+
+    Ok(())
+}
+
+/// Draws the votes of the last window onto the top left corner of the given
+/// area, headed by its winner.
+fn draw_votes(canvas: &mut WindowCanvas, tally: &VoteTally, area: Rect) -> Result<()> {
+    // The size of a font pixel, in drawable pixels
+    let scale = (area.height() / 150).max(1) as i32;
+    let total: usize = tally.votes.iter().map(|(_, count)| count).sum();
+    let label_width = tally.votes.iter().take(MAX_SHOWN_VOTES)
+        .map(|&(action, _)| crowd::vote_name(action).len() * GLYPH_ADVANCE)
+        .max()
+        .unwrap_or(0);
+    let row_height = GLYPH_HEIGHT + 2;
+
+    let mut rects: Vec<(Rect, Color)> = Vec::new();
+    let mut push_rect = |x: usize, y: usize, width: usize, height: usize, color: Color| rects.push((
+        Rect::new(area.x() + (2 + x as i32) * scale, area.y() + (2 + y as i32) * scale, (width as i32 * scale) as u32, (height as i32 * scale) as u32),
+        color,
+    ));
+
+    let winner = tally.winner.map_or("-", crowd::vote_name);
+    let header = format!("NOW: {}", winner.to_ascii_uppercase());
+    overlay::draw_text(&header, |x, y| push_rect(x, y, 1, 1, VOTE_WINNER_COLOR));
+    for (i, &(action, count)) in tally.votes.iter().take(MAX_SHOWN_VOTES).enumerate() {
+        let top = (i + 1) * row_height + 1;
+        let color = if Some(action) == tally.winner { VOTE_WINNER_COLOR } else { VOTE_COLOR };
+        overlay::draw_text(&crowd::vote_name(action).to_ascii_uppercase(), |x, y| push_rect(x, top + y, 1, 1, color));
+        push_rect(label_width + 1, top, (VOTE_BAR_WIDTH * count / total).max(1), GLYPH_HEIGHT, color);
     }
 
+    // Darken the background to keep the votes readable
+    let rows = 1 + tally.votes.len().min(MAX_SHOWN_VOTES);
+    let width = (label_width + 1 + VOTE_BAR_WIDTH).max(header.len() * GLYPH_ADVANCE) as i32 + 2;
+    let backdrop = Rect::new(area.x(), area.y(), (width * scale) as u32, ((rows * row_height + 3) as i32 * scale) as u32);
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(backdrop).map_err(|e| anyhow!("{e}"))?;
+    canvas.set_blend_mode(BlendMode::None);
+
+    for (rect, color) in rects {
+        canvas.set_draw_color(color);
+        canvas.fill_rect(rect).map_err(|e| anyhow!("{e}"))?;
+    }
+    Ok(())
+}
+
+fn is_fullscreen_toggle(scancode: Option<Scancode>, keymod: Mod) -> bool {
+    match scancode {
+        Some(Scancode::F11) => true,
+        Some(Scancode::Return) => keymod.intersects(Mod::LALTMOD | Mod::RALTMOD),
+        _ => false,
+    }
+}
+
+fn is_preview_toggle(scancode: Option<Scancode>, keymod: Mod) -> bool {
+    scancode == Some(Scancode::P) && keymod.intersects(Mod::LALTMOD | Mod::RALTMOD)
+}
+
+fn toggle_fullscreen(window: &mut Window) -> Result<()> {
+    let fullscreen_type = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    window.set_fullscreen(fullscreen_type).map_err(|e| anyhow!("{e}"))?;
     Ok(())
 }
 
@@ -126,45 +526,122 @@ fn convert_mouse_button(sdl_button: SDLMouseButton) -> Option<MouseButton> {
     }
 }
 
-fn convert_key(sdl_key: Option<Keycode>) -> Option<Key> {
-    match sdl_key {
-        Some(Keycode::Left) => Some(Key::ArrowLeft),
-        Some(Keycode::Right) => Some(Key::ArrowRight),
-        Some(Keycode::Up) => Some(Key::ArrowUp),
-        Some(Keycode::Down) => Some(Key::ArrowDown),
-        Some(Keycode::Escape) => Some(Key::Escape),
-        Some(Keycode::Return) => Some(Key::Enter),
-        Some(Keycode::Space) => Some(Key::Space),
-        Some(Keycode::LCtrl) => Some(Key::Ctrl),
-        Some(Keycode::RCtrl) => Some(Key::Ctrl),
-        Some(Keycode::LShift) => Some(Key::Shift),
-        Some(Keycode::RShift) => Some(Key::Shift),
-        Some(Keycode::A) => Some(Key::Letter('A')),
-        Some(Keycode::B) => Some(Key::Letter('B')),
-        Some(Keycode::C) => Some(Key::Letter('C')),
-        Some(Keycode::D) => Some(Key::Letter('D')),
-        Some(Keycode::E) => Some(Key::Letter('E')),
-        Some(Keycode::F) => Some(Key::Letter('F')),
-        Some(Keycode::G) => Some(Key::Letter('G')),
-        Some(Keycode::H) => Some(Key::Letter('H')),
-        Some(Keycode::I) => Some(Key::Letter('I')),
-        Some(Keycode::J) => Some(Key::Letter('J')),
-        Some(Keycode::K) => Some(Key::Letter('K')),
-        Some(Keycode::L) => Some(Key::Letter('L')),
-        Some(Keycode::M) => Some(Key::Letter('M')),
-        Some(Keycode::N) => Some(Key::Letter('N')),
-        Some(Keycode::O) => Some(Key::Letter('O')),
-        Some(Keycode::P) => Some(Key::Letter('P')),
-        Some(Keycode::Q) => Some(Key::Letter('Q')),
-        Some(Keycode::R) => Some(Key::Letter('R')),
-        Some(Keycode::S) => Some(Key::Letter('S')),
-        Some(Keycode::T) => Some(Key::Letter('T')),
-        Some(Keycode::U) => Some(Key::Letter('U')),
-        Some(Keycode::V) => Some(Key::Letter('V')),
-        Some(Keycode::W) => Some(Key::Letter('W')),
-        Some(Keycode::X) => Some(Key::Letter('X')),
-        Some(Keycode::Y) => Some(Key::Letter('Y')),
-        Some(Keycode::Z) => Some(Key::Letter('Z')),
+fn convert_controller_button(sdl_button: Button) -> Option<GamepadButton> {
+    // SDL names the face buttons after their position on an Xbox controller
+    match sdl_button {
+        Button::A => Some(GamepadButton::Cluster(Direction::Down)),
+        Button::B => Some(GamepadButton::Cluster(Direction::Right)),
+        Button::X => Some(GamepadButton::Cluster(Direction::Left)),
+        Button::Y => Some(GamepadButton::Cluster(Direction::Up)),
+        Button::Start => Some(GamepadButton::Menu),
+        Button::Back => Some(GamepadButton::Select),
+        Button::Guide => Some(GamepadButton::Home),
+        Button::LeftShoulder => Some(GamepadButton::Bumper(Direction::Left)),
+        Button::RightShoulder => Some(GamepadButton::Bumper(Direction::Right)),
+        Button::LeftStick => Some(GamepadButton::Stick(GamepadStick::Left)),
+        Button::RightStick => Some(GamepadButton::Stick(GamepadStick::Right)),
+        Button::DPadUp => Some(GamepadButton::DPad(Direction::Up)),
+        Button::DPadDown => Some(GamepadButton::DPad(Direction::Down)),
+        Button::DPadLeft => Some(GamepadButton::DPad(Direction::Left)),
+        Button::DPadRight => Some(GamepadButton::DPad(Direction::Right)),
         _ => None,
     }
 }
+
+fn convert_key(sdl_keycode: Option<Keycode>, sdl_scancode: Option<Scancode>) -> Option<Key> {
+    // Keys that don't type anything and the movement keys go by their physical
+    // position (i.e. scancode), similar to the browser's `KeyboardEvent.code`,
+    // so e.g. WASD is ZQSD on an AZERTY keyboard. Keys that type something go
+    // by what they type (i.e. keycode), so cheats and save game names can be
+    // typed as printed on the keyboard.
+    let key = match sdl_scancode {
+        Some(Scancode::Left) => Some(Key::ArrowLeft),
+        Some(Scancode::Right) => Some(Key::ArrowRight),
+        Some(Scancode::Up) => Some(Key::ArrowUp),
+        Some(Scancode::Down) => Some(Key::ArrowDown),
+        Some(Scancode::Escape) => Some(Key::Escape),
+        Some(Scancode::Return) => Some(Key::Enter),
+        Some(Scancode::KpEnter) => Some(Key::Enter),
+        Some(Scancode::Space) => Some(Key::Space),
+        Some(Scancode::LCtrl) => Some(Key::Ctrl),
+        Some(Scancode::RCtrl) => Some(Key::Ctrl),
+        Some(Scancode::LShift) => Some(Key::Shift),
+        Some(Scancode::RShift) => Some(Key::Shift),
+        Some(Scancode::LAlt) => Some(Key::Alt),
+        Some(Scancode::RAlt) => Some(Key::Alt),
+        Some(Scancode::LGui) => Some(Key::Meta),
+        Some(Scancode::RGui) => Some(Key::Meta),
+        Some(Scancode::Tab) => Some(Key::Tab),
+        Some(Scancode::Backspace) => Some(Key::Backspace),
+        Some(Scancode::CapsLock) => Some(Key::CapsLock),
+        Some(Scancode::NumLockClear) => Some(Key::NumLock),
+        Some(Scancode::ScrollLock) => Some(Key::ScrollLock),
+        Some(Scancode::PrintScreen) => Some(Key::PrintScreen),
+        Some(Scancode::Pause) => Some(Key::Pause),
+        Some(Scancode::Insert) => Some(Key::Insert),
+        Some(Scancode::Delete) => Some(Key::Delete),
+        Some(Scancode::Home) => Some(Key::Home),
+        Some(Scancode::End) => Some(Key::End),
+        Some(Scancode::PageUp) => Some(Key::PageUp),
+        Some(Scancode::PageDown) => Some(Key::PageDown),
+        Some(Scancode::Application) => Some(Key::ContextMenu),
+        Some(Scancode::F1) => Some(Key::Function(1)),
+        Some(Scancode::F2) => Some(Key::Function(2)),
+        Some(Scancode::F3) => Some(Key::Function(3)),
+        Some(Scancode::F4) => Some(Key::Function(4)),
+        Some(Scancode::F5) => Some(Key::Function(5)),
+        Some(Scancode::F6) => Some(Key::Function(6)),
+        Some(Scancode::F7) => Some(Key::Function(7)),
+        Some(Scancode::F8) => Some(Key::Function(8)),
+        Some(Scancode::F9) => Some(Key::Function(9)),
+        Some(Scancode::F10) => Some(Key::Function(10)),
+        Some(Scancode::F11) => Some(Key::Function(11)),
+        Some(Scancode::F12) => Some(Key::Function(12)),
+        Some(Scancode::F13) => Some(Key::Function(13)),
+        Some(Scancode::F14) => Some(Key::Function(14)),
+        Some(Scancode::F15) => Some(Key::Function(15)),
+        Some(Scancode::F16) => Some(Key::Function(16)),
+        Some(Scancode::F17) => Some(Key::Function(17)),
+        Some(Scancode::F18) => Some(Key::Function(18)),
+        Some(Scancode::F19) => Some(Key::Function(19)),
+        Some(Scancode::F20) => Some(Key::Function(20)),
+        Some(Scancode::F21) => Some(Key::Function(21)),
+        Some(Scancode::F22) => Some(Key::Function(22)),
+        Some(Scancode::F23) => Some(Key::Function(23)),
+        Some(Scancode::F24) => Some(Key::Function(24)),
+        Some(Scancode::Kp1) => Some(Key::Numpad('1')),
+        Some(Scancode::Kp2) => Some(Key::Numpad('2')),
+        Some(Scancode::Kp3) => Some(Key::Numpad('3')),
+        Some(Scancode::Kp4) => Some(Key::Numpad('4')),
+        Some(Scancode::Kp5) => Some(Key::Numpad('5')),
+        Some(Scancode::Kp6) => Some(Key::Numpad('6')),
+        Some(Scancode::Kp7) => Some(Key::Numpad('7')),
+        Some(Scancode::Kp8) => Some(Key::Numpad('8')),
+        Some(Scancode::Kp9) => Some(Key::Numpad('9')),
+        Some(Scancode::Kp0) => Some(Key::Numpad('0')),
+        Some(Scancode::KpPlus) => Some(Key::Numpad('+')),
+        Some(Scancode::KpMinus) => Some(Key::Numpad('-')),
+        Some(Scancode::KpMultiply) => Some(Key::Numpad('*')),
+        Some(Scancode::KpDivide) => Some(Key::Numpad('/')),
+        Some(Scancode::KpPeriod) => Some(Key::Numpad('.')),
+        Some(Scancode::KpComma) => Some(Key::Numpad(',')),
+        Some(Scancode::KpEquals) => Some(Key::Numpad('=')),
+        Some(Scancode::W) => Some(Key::Letter('W')),
+        Some(Scancode::A) => Some(Key::Letter('A')),
+        Some(Scancode::S) => Some(Key::Letter('S')),
+        Some(Scancode::D) => Some(Key::Letter('D')),
+        _ => None,
+    };
+    key.or_else(|| match u8::try_from(sdl_keycode?.into_i32()).ok()? {
+        // Keys elsewhere that type a movement letter would move the player too,
+        // so they type the letter of their position instead
+        c @ (b'w' | b'a' | b's' | b'd') => match sdl_scancode?.name().as_bytes() {
+            &[position] if position.is_ascii_uppercase() => Some(Key::Letter(position as char)),
+            _ => Some(Key::Letter(c.to_ascii_uppercase() as char)),
+        },
+        c @ b'a'..=b'z' => Some(Key::Letter(c.to_ascii_uppercase() as char)),
+        c @ b'0'..=b'9' => Some(Key::Digit(c as char)),
+        c @ (b'`' | b'-' | b'=' | b'[' | b']' | b'\\' | b';' | b'\'' | b',' | b'.' | b'/') => Some(Key::Symbol(c as char)),
+        _ => None,
+    })
+}
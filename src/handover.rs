@@ -0,0 +1,187 @@
+//! Handing over the game between the players taking turns (see `queue`) and
+//! counting down their turns on the facade.
+//!
+//! Like `demo`, this accesses the engine's C globals and must only be used
+//! from the DOOM thread.
+
+use std::{ffi::{c_char, c_int, CStr}, fs, path::PathBuf, time::{Duration, Instant}};
+
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{attract::AttractMode, demo, message::{HandoverMessage, PlayerId}, overlay::Overlay, queue::{HandoverMode, QueueOptions}};
+
+/// The save slot used for swapping games. The menu only uses slots 0 to 5,
+/// while the engine supports up to 7.
+const SWAP_SLOT: c_int = 7;
+/// How long to wait for DOOM to save a game before giving up on it.
+const SAVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+extern "C" {
+    static mut vanilla_savegame_limit: c_int;
+
+    fn G_SaveGame(slot: c_int, description: *const c_char);
+    fn P_SaveGameFile(slot: c_int) -> *const c_char;
+}
+
+/// A game that DOOM is about to save at the end of a turn.
+#[derive(Debug)]
+struct PendingSave {
+    /// The player whose game is being saved.
+    player: PlayerId,
+    /// The player to hand over to once the game is saved.
+    next: Option<PlayerId>,
+    path: PathBuf,
+    since: Instant,
+}
+
+/// Hands over the game when the queue says so. This lives on the DOOM thread.
+#[derive(Debug)]
+pub struct Handover {
+    rx: mpsc::Receiver<HandoverMessage>,
+    options: QueueOptions,
+    /// The number of the current player and when their turn ends.
+    turn: Option<(u64, Instant)>,
+    overlay: Option<Overlay>,
+    /// The skill and episode to start new games with.
+    game: Option<(c_int, c_int)>,
+    pending_save: Option<PendingSave>,
+}
+
+impl Handover {
+    pub fn new(rx: mpsc::Receiver<HandoverMessage>, options: QueueOptions) -> Self {
+        Self {
+            rx,
+            options,
+            turn: None,
+            overlay: None,
+            game: None,
+            pending_save: None,
+        }
+    }
+
+    /// The number of the player whose turn it is, if any.
+    pub fn player_number(&self) -> Option<u64> {
+        self.turn.map(|(number, _)| number)
+    }
+
+    /// The overlay with the countdown, if it's someone's turn.
+    pub fn overlay(&self) -> Option<&Overlay> {
+        self.overlay.as_ref()
+    }
+
+    /// Hands over the game and updates the countdown. Should be called once
+    /// per frame.
+    pub fn update(&mut self, attract: &mut AttractMode) {
+        while let Ok(message) = self.rx.try_recv() {
+            match message {
+                HandoverMessage::Start { previous, player, number, ends_at } => {
+                    self.turn = Some((number, ends_at));
+                    if let Some(game) = attract.cancel() {
+                        self.game = Some(game);
+                    }
+                    self.hand_over(previous, Some(player));
+                },
+                HandoverMessage::Extend { ends_at } => if let Some((_, turn_ends_at)) = &mut self.turn {
+                    *turn_ends_at = ends_at;
+                },
+                HandoverMessage::End { previous } => {
+                    self.turn = None;
+                    self.hand_over(Some(previous), None);
+                },
+            }
+        }
+
+        self.poll_pending_save();
+
+        let text = self.turn.map(|(number, ends_at)| {
+            let secs = ends_at.saturating_duration_since(Instant::now()).as_secs();
+            format!("#{number} {}:{:02}", secs / 60, secs % 60)
+        });
+        if self.overlay.as_ref().map(Overlay::text) != text.as_deref() {
+            self.overlay = text.map(Overlay::new);
+        }
+    }
+
+    fn hand_over(&mut self, previous: Option<PlayerId>, next: Option<PlayerId>) {
+        if let Some(pending_save) = &mut self.pending_save {
+            // Still saving the game of the player before
+            pending_save.next = next;
+            return;
+        }
+        if demo::is_in_level() {
+            self.game = Some(demo::current_game());
+        }
+        match previous {
+            Some(player) if self.options.queue_handover == HandoverMode::Swap && demo::is_in_level() => {
+                // DOOM saves the game with the next tic
+                let path = unsafe {
+                    // Vanilla DOOM quits if the game doesn't fit into its buffer
+                    vanilla_savegame_limit = 0;
+                    PathBuf::from(CStr::from_ptr(P_SaveGameFile(SWAP_SLOT)).to_string_lossy().into_owned())
+                };
+                _ = fs::remove_file(&path);
+                unsafe { G_SaveGame(SWAP_SLOT, c"lidoom".as_ptr()); }
+                self.pending_save = Some(PendingSave { player, next, path, since: Instant::now() });
+            },
+            _ => self.resume(next),
+        }
+    }
+
+    fn poll_pending_save(&mut self) {
+        let Some(pending_save) = &self.pending_save else { return };
+        // DOOM writes to a temporary file and moves it into the slot once done
+        if pending_save.path.exists() {
+            let path = self.save_path(&pending_save.player);
+            let result = fs::create_dir_all(&self.options.queue_save_dir)
+                .and_then(|()| fs::rename(&pending_save.path, &path));
+            match result {
+                Ok(()) => info!("Saved the game of {:?} to {}", pending_save.player, path.display()),
+                Err(e) => warn!("Could not move the game of {:?} to {}: {e}", pending_save.player, path.display()),
+            }
+        } else if pending_save.since.elapsed() >= SAVE_TIMEOUT {
+            warn!("Could not save the game of {:?}, DOOM did not save it in time", pending_save.player);
+        } else {
+            return;
+        }
+        let next = self.pending_save.take().and_then(|pending_save| pending_save.next);
+        self.resume(next);
+    }
+
+    /// Starts the next player's game or returns to the title loop if nobody is
+    /// waiting.
+    fn resume(&mut self, next: Option<PlayerId>) {
+        let Some(player) = next else {
+            demo::start_title_loop();
+            return;
+        };
+        if self.options.queue_handover == HandoverMode::Swap {
+            let path = self.save_path(&player);
+            if path.exists() {
+                match demo::load_game(&path) {
+                    Ok(()) => {
+                        info!("Resuming the game of {:?} from {}", player, path.display());
+                        return;
+                    },
+                    Err(e) => warn!("Could not load the game of {:?}: {e:#}", player),
+                }
+            }
+        }
+        let (skill, episode) = self.game.unwrap_or_else(demo::current_game);
+        demo::new_game(skill, episode);
+    }
+
+    /// The file that the player's game is kept in when swapping games.
+    fn save_path(&self, player: &PlayerId) -> PathBuf {
+        let name = match player {
+            PlayerId::Lighthouse(source) => {
+                let source: String = source.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+                    .collect();
+                format!("lighthouse_{source}.dsg")
+            },
+            PlayerId::Web(ticket) => format!("web_{ticket:016x}.dsg"),
+        };
+        self.options.queue_save_dir.join(name)
+    }
+}
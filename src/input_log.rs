@@ -0,0 +1,290 @@
+//! Logging and replaying input, e.g. to reproduce issues reported by players.
+//!
+//! An input log consists of one JSON object per line, each with the time since
+//! the start of the session in milliseconds, the DOOM tic (counting from DOOM's
+//! first frame) and either a `ControllerMessage` along with its source or a
+//! `MapperMessage` that was sent to DOOM:
+//!
+//! ```json
+//! {"time_ms":1520,"tic":53,"controller":{"source":{"lighthouse":"0"},"message":{"Key":{"key":"Shift","down":true}}}}
+//! {"time_ms":1520,"tic":53,"mapper":{"message":{"Action":{"action":"Speed","down":true}}}}
+//! ```
+//!
+//! Replays are keyed to the tics, i.e. the DOOM thread passes on the logged
+//! input once the game reaches its tic, so they keep in step with the game
+//! even if it stalls. Logs from older versions, which lack the tics, are
+//! replayed by their times instead, and those whose sources are just their kind
+//! (e.g. `"source":"lighthouse"`) with all clients of a kind as a single source.
+
+use std::{collections::VecDeque, fs::File, io::{BufRead, BufReader, BufWriter, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::{sync::mpsc::{self, error::TrySendError}, time::Instant};
+use tracing::{info, warn};
+
+use crate::{constants::DOOM_TICK_RATE, message::{ControllerInput, ControllerMessage, InputSource, MapperMessage}, state};
+
+/// Settings for logging and replaying input.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Recording")]
+pub struct InputLogOptions {
+    /// A file to log all input and the resulting actions to (as JSON lines), e.g. for reproducing bugs.
+    #[arg(long, env = "LIDOOM_RECORD_INPUT")]
+    pub record_input: Option<PathBuf>,
+    /// An input log to replay into the mapper instead of the live input, as created with --record-input.
+    #[arg(long, env = "LIDOOM_REPLAY_INPUT")]
+    pub replay_input: Option<PathBuf>,
+}
+
+/// A logged message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputLogEvent {
+    Controller {
+        #[serde(deserialize_with = "deserialize_source")]
+        source: InputSource,
+        message: ControllerMessage,
+    },
+    Mapper { message: MapperMessage },
+}
+
+/// Deserializes a source, falling back to the plain kinds of older logs.
+fn deserialize_source<'de, D>(deserializer: D) -> std::result::Result<InputSource, D::Error> where D: Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum LoggedSource {
+        Source(InputSource),
+        Kind(String),
+    }
+
+    match LoggedSource::deserialize(deserializer)? {
+        LoggedSource::Source(source) => Ok(source),
+        LoggedSource::Kind(kind) => match kind.as_str() {
+            "lighthouse" => Ok(InputSource::Lighthouse(String::new())),
+            "websocket" => Ok(InputSource::WebSocket(0)),
+            _ => Err(de::Error::unknown_variant(&kind, &["lighthouse", "gui", "websocket"])),
+        },
+    }
+}
+
+/// A line of the input log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputLogEntry {
+    pub time_ms: u64,
+    /// Missing in logs from older versions.
+    #[serde(default)]
+    pub tic: Option<u64>,
+    #[serde(flatten)]
+    pub event: InputLogEvent,
+}
+
+impl InputLogEntry {
+    /// The tic to replay the entry at.
+    fn replay_tic(&self) -> u64 {
+        self.tic.unwrap_or(self.time_ms * DOOM_TICK_RATE as u64 / 1000)
+    }
+}
+
+/// The current DOOM tic, counting from DOOM's first frame, which the DOOM
+/// thread advances and the taps log their messages with.
+#[derive(Debug, Clone, Default)]
+pub struct TicClock(Arc<AtomicU64>);
+
+impl TicClock {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, tic: u64) {
+        self.0.store(tic, Ordering::Relaxed);
+    }
+}
+
+/// A handle for logging messages that is shared between the taps.
+#[derive(Debug, Clone)]
+pub struct InputLog {
+    tx: Option<mpsc::Sender<InputLogEntry>>,
+    clock: TicClock,
+    start: Instant,
+}
+
+impl InputLog {
+    /// Creates a handle that logs to the given channel or, if `None`, discards
+    /// the messages.
+    pub fn new(tx: Option<mpsc::Sender<InputLogEntry>>, clock: TicClock) -> Self {
+        Self { tx, clock, start: Instant::now() }
+    }
+
+    async fn log(&self, event: InputLogEvent) -> Result<()> {
+        if let Some(tx) = &self.tx {
+            let time_ms = u64::try_from(self.start.elapsed().as_millis()).unwrap_or(u64::MAX);
+            tx.send(InputLogEntry { time_ms, tic: Some(self.clock.get()), event }).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Forwards the messages from a controller to the mapper, logging them with
+/// their source. Without a mapper to forward to (e.g. while replaying), the
+/// messages are only logged.
+pub async fn tap_controller(
+    mut rx: mpsc::Receiver<ControllerInput>,
+    tx: Option<mpsc::Sender<ControllerInput>>,
+    log: InputLog,
+) -> Result<()> {
+    while let Some(input) = rx.recv().await {
+        log.log(InputLogEvent::Controller { source: input.source.clone(), message: input.message }).await?;
+        if let Some(tx) = &tx {
+            tx.send(input).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forwards the messages from the mapper (and console) to DOOM, logging them.
+pub async fn tap_mapper(
+    mut rx: mpsc::Receiver<MapperMessage>,
+    tx: mpsc::Sender<MapperMessage>,
+    log: InputLog,
+) -> Result<()> {
+    while let Some(message) = rx.recv().await {
+        log.log(InputLogEvent::Mapper { message }).await?;
+        tx.send(message).await?;
+    }
+    Ok(())
+}
+
+/// Reads all entries of the input log at the given path.
+pub fn read_all(path: &Path) -> Result<Vec<InputLogEntry>> {
+    let file = File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let mut entries = Vec::new();
+    let mut lines = BufReader::new(file).lines().enumerate().peekable();
+    while let Some((i, line)) = lines.next() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            // Logs may be cut off if lidoom didn't exit cleanly
+            Err(e) if e.is_eof() && lines.peek().is_none() => {
+                warn!("Input log ends with an incomplete line, ignoring it");
+            },
+            Err(e) => return Err(e).with_context(|| format!("Invalid entry in line {}", i + 1)),
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes the logged messages to the given path.
+pub async fn run(mut rx: mpsc::Receiver<InputLogEntry>, path: PathBuf) -> Result<()> {
+    let file = File::create(&path).with_context(|| format!("Could not create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    info!("Logging input to {}", path.display());
+
+    while let Some(entry) = rx.recv().await {
+        serde_json::to_writer(&mut writer, &entry)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Advances the tic clock and sends the logged controller messages to the
+/// mapper at their original tics and from their original sources. This lives
+/// on the DOOM thread.
+#[derive(Debug)]
+pub struct InputReplay {
+    clock: TicClock,
+    /// The game tic of DOOM's first frame.
+    first_tic: Option<u64>,
+    tx: Option<mpsc::Sender<ControllerInput>>,
+    pending: VecDeque<(u64, ControllerInput)>,
+}
+
+impl InputReplay {
+    /// Creates a replay of the input log at the given path that sends to the
+    /// given channel or, if `None`, only advances the clock.
+    pub fn new(clock: TicClock, replay: Option<(&Path, mpsc::Sender<ControllerInput>)>) -> Result<Self> {
+        let mut tx = None;
+        let mut pending = VecDeque::new();
+        if let Some((path, replay_tx)) = replay {
+            pending = read_all(path)?.into_iter()
+                .filter_map(|entry| {
+                    let tic = entry.replay_tic();
+                    match entry.event {
+                        InputLogEvent::Controller { source, message } => Some((tic, ControllerInput { source, message })),
+                        InputLogEvent::Mapper { .. } => None,
+                    }
+                })
+                .collect();
+            pending.make_contiguous().sort_by_key(|&(tic, _)| tic);
+            info!("Replaying {} input messages from {}", pending.len(), path.display());
+            tx = Some(replay_tx);
+        }
+        Ok(Self { clock, first_tic: None, tx, pending })
+    }
+
+    /// Advances the clock and sends the messages that are due. Should be
+    /// called once per frame.
+    pub fn update(&mut self) {
+        let game_tic = state::game_tic();
+        let tic = game_tic - *self.first_tic.get_or_insert(game_tic);
+        self.clock.set(tic);
+
+        let Some(tx) = &self.tx else { return };
+        while let Some((_, input)) = self.pending.front().filter(|&&(due, _)| due <= tic) {
+            // Waiting for the mapper could deadlock with it waiting for us, so
+            // a burst of messages may spill over into the next frames
+            match tx.try_send(input.clone()) {
+                Ok(()) => _ = self.pending.pop_front(),
+                Err(TrySendError::Full(_)) => return,
+                Err(TrySendError::Closed(_)) => {
+                    warn!("Stopped replaying input, the mapper is gone");
+                    self.tx = None;
+                    return;
+                },
+            }
+        }
+        if self.pending.is_empty() {
+            info!("Finished replaying input");
+            self.tx = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::{ControllerMessage, InputSource, Key};
+
+    use super::{InputLogEntry, InputLogEvent};
+
+    fn parse_source(source: &str) -> InputSource {
+        let line = format!(r#"{{"time_ms":0,"controller":{{"source":{source},"message":{{"Key":{{"key":"Shift","down":true}}}}}}}}"#);
+        let entry: InputLogEntry = serde_json::from_str(&line).unwrap();
+        let InputLogEvent::Controller { source, message } = entry.event else { panic!("Not a controller entry") };
+        assert_eq!(message, ControllerMessage::Key { key: Key::Shift, down: true });
+        source
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(parse_source(r#"{"lighthouse":"3"}"#), InputSource::Lighthouse("3".into()));
+        assert_eq!(parse_source(r#""gui""#), InputSource::Gui);
+        assert_eq!(parse_source(r#"{"websocket":42}"#), InputSource::WebSocket(42));
+    }
+
+    #[test]
+    fn legacy_sources() {
+        assert_eq!(parse_source(r#""lighthouse""#), InputSource::Lighthouse(String::new()));
+        assert_eq!(parse_source(r#""websocket""#), InputSource::WebSocket(0));
+    }
+
+    #[test]
+    fn unknown_source() {
+        let line = r#"{"time_ms":0,"controller":{"source":"keyboard","message":"Release"}}"#;
+        assert!(serde_json::from_str::<InputLogEntry>(line).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! A kiosk mode for leaving the game running unattended, e.g. at public
+//! events.
+//!
+//! Like `state` and `demo`, this accesses the engine's C globals and must only
+//! be used from the DOOM thread.
+
+use std::{ffi::{c_char, c_int, c_short}, ptr, time::{Duration, Instant}};
+
+use clap::{Args, ValueEnum};
+use doomgeneric::input::{keys::KEY_USE, KeyData};
+use tracing::info;
+
+use crate::{demo, state};
+
+/// The index of the load game item in the main menu, see `main_e`.
+const MAIN_MENU_LOAD_GAME: usize = 2;
+/// The index of the save game item in the main menu.
+const MAIN_MENU_SAVE_GAME: usize = 3;
+
+/// Mirrors `menuitem_t` from `m_menu.c`.
+#[repr(C)]
+#[allow(dead_code)]
+struct MenuItem {
+    status: c_short,
+    name: [c_char; 10],
+    routine: Option<extern "C" fn(c_int)>,
+    alpha_key: c_char,
+}
+
+/// Mirrors `menu_t` from `m_menu.c`.
+#[repr(C)]
+#[allow(dead_code)]
+struct Menu {
+    numitems: c_short,
+    prev_menu: *const Menu,
+    menuitems: *mut MenuItem,
+    routine: Option<extern "C" fn()>,
+    x: c_short,
+    y: c_short,
+    last_on: c_short,
+}
+
+extern "C" {
+    static mut MainMenu: [MenuItem; 6];
+    static mut MainDef: Menu;
+    static key_menu_save: c_int;
+    static key_menu_load: c_int;
+    static key_menu_qsave: c_int;
+    static key_menu_qload: c_int;
+    static key_menu_quit: c_int;
+}
+
+/// Settings for the kiosk mode.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Kiosk mode")]
+pub struct KioskOptions {
+    /// Whether to run in kiosk mode, which blocks quitting, saving, loading and cheats and respawns automatically.
+    #[arg(long, env = "LIDOOM_KIOSK")]
+    pub kiosk: bool,
+    /// Features to keep available in kiosk mode.
+    #[arg(long, env = "LIDOOM_KIOSK_ALLOW", value_delimiter = ',', requires = "kiosk")]
+    pub kiosk_allow: Vec<KioskFeature>,
+    /// The number of seconds without input after which the game is reset (see --kiosk-idle-action).
+    #[arg(long, env = "LIDOOM_KIOSK_IDLE_RESET", requires = "kiosk")]
+    pub kiosk_idle_reset: Option<u64>,
+    /// What to do once the game has been idle for --kiosk-idle-reset seconds.
+    #[arg(long, env = "LIDOOM_KIOSK_IDLE_ACTION", default_value = "title")]
+    pub kiosk_idle_action: IdleAction,
+    /// The number of seconds after dying after which the player respawns automatically, or 0 to respawn manually.
+    #[arg(long, env = "LIDOOM_KIOSK_RESPAWN_AFTER", default_value_t = 5)]
+    pub kiosk_respawn_after: u64,
+}
+
+/// A feature that kiosk mode can keep available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KioskFeature {
+    /// Quitting via the menu or F10.
+    Quit,
+    /// Saving via the menu, F2 or F6.
+    Save,
+    /// Loading via the menu, F3 or F9.
+    Load,
+    /// Typing cheats, e.g. iddqd.
+    Cheats,
+}
+
+/// What to do once the game has been idle for too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IdleAction {
+    /// Return to the title screen.
+    Title,
+    /// Restart the current level.
+    RestartLevel,
+}
+
+/// Enforces the kiosk mode. This lives on the DOOM thread and sees every key
+/// before it is passed to DOOM.
+#[derive(Debug)]
+pub struct Kiosk {
+    options: KioskOptions,
+    menu_restricted: bool,
+    last_input: Instant,
+    last_key: Option<u8>,
+    dead_since: Option<Instant>,
+    use_held: bool,
+    pending_key: Option<KeyData>,
+}
+
+impl Kiosk {
+    pub fn new(options: KioskOptions) -> Self {
+        Self {
+            options,
+            menu_restricted: false,
+            last_input: Instant::now(),
+            last_key: None,
+            dead_since: None,
+            use_held: false,
+            pending_key: None,
+        }
+    }
+
+    fn allows(&self, feature: KioskFeature) -> bool {
+        !self.options.kiosk || self.options.kiosk_allow.contains(&feature)
+    }
+
+    /// Checks whether a key may be passed to DOOM.
+    pub fn filter(&mut self, key_data: &KeyData) -> bool {
+        if !key_data.pressed {
+            return true;
+        }
+        self.last_input = Instant::now();
+        let key = key_data.key as c_int;
+
+        // SAFETY: The bindings are only mutated by the DOOM thread (while
+        // loading the config), which we are running on.
+        let blocked = unsafe {
+            (!self.allows(KioskFeature::Save) && [key_menu_save, key_menu_qsave].contains(&key))
+                || (!self.allows(KioskFeature::Load) && [key_menu_load, key_menu_qload].contains(&key))
+                || (!self.allows(KioskFeature::Quit) && key == key_menu_quit)
+        };
+        // Every cheat starts with 'id', so breaking up that sequence disables all of them
+        let cheat = !self.allows(KioskFeature::Cheats) && self.last_key == Some(b'i') && key_data.key == b'd';
+
+        if blocked || cheat {
+            return false;
+        }
+        // Only track what DOOM sees, otherwise repeating the blocked 'd' would
+        // complete the sequence
+        self.last_key = Some(key_data.key);
+        true
+    }
+
+    /// Takes a key that the kiosk mode presses on its own, e.g. to respawn.
+    pub fn take_key(&mut self) -> Option<KeyData> {
+        self.pending_key.take()
+    }
+
+    /// Respawns the player and resets the game once idle. Should be called
+    /// once per frame.
+    pub fn update(&mut self) {
+        if !self.options.kiosk {
+            return;
+        }
+        if !self.menu_restricted {
+            self.restrict_menu();
+        }
+
+        if self.use_held {
+            self.pending_key = Some(KeyData { pressed: false, key: *KEY_USE });
+            self.use_held = false;
+        } else if self.options.kiosk_respawn_after > 0 && state::player_status().dead && !demo::is_showing_demos() {
+            // Respawn by pressing use like a player would, so demos capture it too
+            let dead_since = *self.dead_since.get_or_insert_with(Instant::now);
+            if dead_since.elapsed() >= Duration::from_secs(self.options.kiosk_respawn_after) {
+                info!("Respawning");
+                self.pending_key = Some(KeyData { pressed: true, key: *KEY_USE });
+                self.use_held = true;
+                self.dead_since = None;
+            }
+        } else {
+            self.dead_since = None;
+        }
+
+        if let Some(secs) = self.options.kiosk_idle_reset {
+            if self.last_input.elapsed() >= Duration::from_secs(secs) {
+                self.last_input = Instant::now();
+                if !demo::is_showing_demos() && !demo::is_recording() {
+                    info!("Resetting the game after {secs} s without input");
+                    match self.options.kiosk_idle_action {
+                        IdleAction::Title => demo::start_title_loop(),
+                        IdleAction::RestartLevel => demo::restart_level(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the quit item from the main menu and disables the save and
+    /// load items, unless they are allowed.
+    fn restrict_menu(&mut self) {
+        // SAFETY: The menus are only accessed by the DOOM thread, which we are
+        // running on, and have been set up during init (which reorders the
+        // items for some game modes, but always keeps quit last).
+        unsafe {
+            let items = &mut *ptr::addr_of_mut!(MainMenu);
+            if !self.allows(KioskFeature::Quit) {
+                (*ptr::addr_of_mut!(MainDef)).numitems -= 1;
+            }
+            for (feature, index) in [(KioskFeature::Load, MAIN_MENU_LOAD_GAME), (KioskFeature::Save, MAIN_MENU_SAVE_GAME)] {
+                if !self.allows(feature) {
+                    // Skip the item with the cursor and ignore its hotkey
+                    items[index].status = -1;
+                    items[index].routine = None;
+                }
+            }
+        }
+        self.menu_restricted = true;
+    }
+}
@@ -1,74 +1,354 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Result};
+use bridge::BridgeTarget;
+use clap::{Parser, Subcommand};
 use doom::LighthouseDoom;
+use handover::Handover;
+use publish::StatePublisher;
+use facade::FacadeSettings;
+use input_log::{InputLog, InputReplay, TicClock};
+use futures::{future::BoxFuture, FutureExt};
 use lighthouse_client::{protocol::Authentication, Lighthouse, LIGHTHOUSE_URL};
-use tracing::info;
-use tokio::{runtime::Runtime, sync::mpsc, task};
-use std::thread;
+use message::UpdaterMessage;
+use queue::Queue;
+use tracing::{info, warn};
+use tokio::{runtime::Runtime, sync::{mpsc, watch}, task};
+use std::{future::Future, thread};
 
+mod analog;
+mod arbiter;
+mod attract;
+mod bridge;
+mod console;
 mod constants;
 mod controller;
+mod crowd;
+mod demo;
 mod doom;
+mod export;
+mod facade;
+mod framebuffer;
 #[cfg(feature = "gui")]
 mod gui;
+mod handover;
+mod input_log;
+mod kiosk;
 mod mapper;
 mod message;
+mod overlay;
+mod preview;
+mod publish;
+mod queue;
+mod recording;
+mod replay;
+mod screenshot;
+mod state;
 mod updater;
+mod video;
+mod web;
 
 #[derive(Parser)]
-#[command(version)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Args {
     /// The username.
-    #[arg(short, long, env = "LIGHTHOUSE_USER")]
-    username: String,
+    #[arg(short, long, env = "LIGHTHOUSE_USER", required = true)]
+    username: Option<String>,
     /// The API token.
-    #[arg(short, long, env = "LIGHTHOUSE_TOKEN")]
-    token: String,
+    #[arg(short, long, env = "LIGHTHOUSE_TOKEN", required = true)]
+    token: Option<String>,
     /// The server URL.
     #[arg(long, env = "LIGHTHOUSE_URL", default_value = LIGHTHOUSE_URL)]
     url: String,
+    /// How the facade is rendered in the GUI preview and screenshots, e.g. window=8x10,gap=4x10,glow=0.5.
+    #[arg(long, env = "LIDOOM_FACADE", default_value = "glow=0.5")]
+    facade: FacadeSettings,
+    #[command(flatten)]
+    mapper: mapper::MapperOptions,
+    #[command(flatten)]
+    screenshot: screenshot::ScreenshotOptions,
+    #[command(flatten)]
+    demo: demo::DemoOptions,
+    #[command(flatten)]
+    attract: attract::AttractOptions,
+    #[command(flatten)]
+    kiosk: kiosk::KioskOptions,
+    #[command(flatten)]
+    queue: queue::QueueOptions,
+    #[command(flatten)]
+    crowd: crowd::CrowdOptions,
+    #[command(flatten)]
+    web: web::WebOptions,
+    #[command(flatten)]
+    preview: preview::PreviewOptions,
+    #[command(flatten)]
+    publish: publish::PublishOptions,
+    #[command(flatten)]
+    recording: recording::RecordingOptions,
+    #[command(flatten)]
+    export: export::ExportOptions,
+    #[command(flatten)]
+    video: video::VideoOptions,
+    #[command(flatten)]
+    input_log: input_log::InputLogOptions,
+    #[cfg(feature = "gui")]
+    #[command(flatten)]
+    gui: gui::GuiOptions,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Replays a recording of lighthouse frames without running DOOM.
+    Replay(replay::ReplayOptions),
+    /// Exports a recording of lighthouse frames as an animated GIF or APNG.
+    Export(export::ExportCommandOptions),
+}
+
+impl Args {
+    fn authentication(&self) -> Result<Authentication> {
+        // Only required by clap if no subcommand is given
+        let (Some(username), Some(token)) = (&self.username, &self.token) else {
+            bail!("The username and token are required (pass --username and --token or set LIGHTHOUSE_USER and LIGHTHOUSE_TOKEN)");
+        };
+        Ok(Authentication::new(username, token))
+    }
 }
 
 fn main() -> Result<()> {
     tracing_subscriber::fmt().init();
     _ = dotenvy::dotenv();
 
-    let args = Args::parse();
-    let auth = Authentication::new(&args.username, &args.token);
+    let mut args = Args::parse();
+    match args.command.take() {
+        Some(Command::Replay(options)) => run_replay(args, options),
+        Some(Command::Export(options)) => export::export_recording(options, args.facade),
+        None => run_game(args),
+    }
+}
+
+/// The task of an output, e.g. the recorder.
+type OutputTask = BoxFuture<'static, ()>;
+
+/// Sets up the enabled outputs that consume the same frames as the updater,
+/// returning the senders to them along with their (not yet spawned) tasks.
+/// Setting up an output (e.g. creating its file) fails right away, whereas a
+/// failing task only logs its error, since the game can go on without it.
+/// Must be called within the Tokio runtime.
+fn frame_outputs(args: &Args) -> Result<(Vec<mpsc::Sender<UpdaterMessage>>, Vec<OutputTask>)> {
+    let mut txs = Vec::new();
+    let mut tasks = Vec::new();
+    if let Some(path) = &args.recording.record_frames {
+        let (tx, rx) = mpsc::channel(8);
+        txs.push(tx);
+        tasks.push(log_failure("Recording frames", recording::start(rx, path.clone())?).boxed());
+    }
+    if let Some(path) = &args.export.export {
+        let (tx, rx) = mpsc::channel(8);
+        txs.push(tx);
+        tasks.push(log_failure("Exporting", export::start(rx, args.export.clone(), path.clone(), args.facade)?).boxed());
+    }
+    if let Some(path) = &args.video.video {
+        let (tx, rx) = mpsc::channel(8);
+        txs.push(tx);
+        tasks.push(log_failure("Capturing video", video::start(rx, args.video.clone(), path.clone())?).boxed());
+    }
+    Ok((txs, tasks))
+}
+
+async fn log_failure(what: &str, task: impl Future<Output = Result<()>>) {
+    if let Err(e) = task.await {
+        warn!("{what} failed: {e:#}");
+    }
+}
+
+fn run_replay(args: Args, options: replay::ReplayOptions) -> Result<()> {
+    let auth = args.authentication()?;
+
+    let (updater_tx, updater_rx) = mpsc::channel(8);
+    let rt = Runtime::new()?;
+    let (output_txs, outputs) = {
+        let _guard = rt.enter();
+        frame_outputs(&args)?
+    };
+    let updater_txs = [vec![updater_tx], output_txs].concat();
+
+    rt.block_on(async move {
+        let lh = Lighthouse::connect_with_tokio_to(&args.url, auth).await?;
+        info!("Connected to the Lighthouse server");
+
+        let updater_handle = task::spawn(updater::run(lh, updater_rx, None));
+        let output_handles: Vec<_> = outputs.into_iter().map(task::spawn).collect();
+
+        replay::run(updater_txs, options).await?;
+
+        updater_handle.await??;
+        for output_handle in output_handles {
+            output_handle.await?;
+        }
+        Ok(())
+    })
+}
+
+fn run_game(args: Args) -> Result<()> {
+    let auth = args.authentication()?;
 
     #[cfg(feature = "gui")]
     let (gui_tx, gui_rx) = mpsc::channel(8);
     let (updater_tx, updater_rx) = mpsc::channel(8);
+    // The outputs are set up before DOOM starts, so e.g. a missing ffmpeg
+    // fails right away
+    let rt = Runtime::new()?;
+    let (mut output_txs, outputs) = {
+        let _guard = rt.enter();
+        frame_outputs(&args)?
+    };
+    let (preview_tx, preview_rx) = mpsc::channel(8);
+    let (preview_frame_tx, preview_frame_rx) = watch::channel(None);
+    if args.preview.preview {
+        output_txs.push(preview_tx);
+    }
+    let (screenshot_tx, screenshot_rx) = mpsc::channel(8);
+    let (state_tx, state_rx) = mpsc::channel(8);
+    let state_path = args.publish.publish_state.clone().zip(args.username.clone())
+        .map(|(resource, username)| vec!["user".to_owned(), username, resource]);
     let (mapper_tx, mapper_rx) = mpsc::channel(8);
+    let (mapper_output_tx, mapper_output_rx) = mpsc::channel(8);
     let (controller_tx, controller_rx) = mpsc::channel(8);
+    let (lighthouse_input_tx, lighthouse_input_rx) = mpsc::channel(8);
+    let (web_input_tx, web_input_rx) = mpsc::channel(8);
+    #[cfg(feature = "gui")]
+    let (gui_input_tx, gui_input_rx) = mpsc::channel(8);
+    let (queue_tx, queue_rx) = mpsc::channel(8);
+    let (queue_state_tx, queue_state_rx) = watch::channel(Queue::default());
+    let (handover_tx, handover_rx) = mpsc::channel(8);
+    let (crowd_tx, crowd_rx) = mpsc::channel(8);
+    #[cfg(feature = "gui")]
+    let crowd_gui_tx = args.crowd.crowd.is_some().then(|| gui_tx.clone());
+
+    // With the queue, the GUI's input only passes if it's the turn of a player
+    // from the web page, in which case the queue forwards it to the tap
+    #[cfg(feature = "gui")]
+    let (gui_input_tx, local_input) = if args.queue.queue {
+        let (queued_tx, queued_rx) = mpsc::channel(8);
+        (queued_tx, Some((queued_rx, gui_input_tx)))
+    } else {
+        (gui_input_tx, None)
+    };
+    #[cfg(not(feature = "gui"))]
+    let local_input = None;
+
+    let web_listener = args.web.web.map(web::bind).transpose()?;
+
+    // All input passes through taps that log it if requested
+    let (input_log_tx, input_log_rx) = mpsc::channel(8);
+    let tic_clock = TicClock::default();
+    let input_log = InputLog::new(args.input_log.record_input.is_some().then_some(input_log_tx), tic_clock.clone());
+    let input_replay = InputReplay::new(tic_clock, args.input_log.replay_input.as_deref().map(|path| (path, controller_tx.clone())))?;
 
     let doom = LighthouseDoom::new(
         #[cfg(feature = "gui")]
         gui_tx,
         updater_tx,
+        output_txs,
+        screenshot_tx,
         mapper_rx,
+        args.demo.clone(),
+        args.attract.clone(),
+        args.kiosk.clone(),
+        Handover::new(handover_rx, args.queue.clone()),
+        StatePublisher::new(state_path.is_some().then_some(state_tx), &args.publish),
+        input_replay,
     );
 
-    let tokio_handle = {
-        let controller_tx = controller_tx.clone();
-        thread::Builder::new().name("Tokio".into()).spawn(move || {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async move {
-                let lh = Lighthouse::connect_with_tokio_to(&args.url, auth).await.unwrap();
-                info!("Connected to the Lighthouse server");
+    let tokio_handle = thread::Builder::new().name("Tokio".into()).spawn(move || {
+        rt.block_on(async move {
+            let lh = Lighthouse::connect_with_tokio_to(&args.url, auth).await.unwrap();
+            info!("Connected to the Lighthouse server");
 
-                let input = lh.stream_input().await.unwrap();
+            let input = lh.stream_input().await.unwrap();
 
-                let updater_handle = task::spawn(updater::run(lh, updater_rx));
-                let mapper_handle = task::spawn(mapper::run(controller_rx, mapper_tx));
-                let controller_handle = task::spawn(controller::run(input, controller_tx));
+            // While replaying an input log, live input is only logged
+            let live_controller_tx = || args.input_log.replay_input.is_none().then(|| controller_tx.clone());
 
-                updater_handle.await.unwrap().unwrap();
-                mapper_handle.await.unwrap().unwrap();
-                controller_handle.await.unwrap().unwrap();
+            let updater_handle = task::spawn(updater::run(lh, updater_rx, state_path.map(|path| (state_rx, path))));
+            let output_handles: Vec<_> = outputs.into_iter().map(task::spawn).collect();
+            let preview_handle = args.preview.preview.then(|| task::spawn(preview::run(preview_rx, preview_frame_tx)));
+            let screenshot_handle = task::spawn(screenshot::run(screenshot_rx, args.screenshot, args.facade));
+            let input_log_handle = args.input_log.record_input.map(|path| task::spawn(input_log::run(input_log_rx, path)));
+            let console_handle = task::spawn(console::run(mapper_output_tx.clone()));
+            let mapper_tap_handle = task::spawn(input_log::tap_mapper(mapper_output_rx, mapper_tx, input_log.clone()));
+            let crowd_handle = args.crowd.crowd.map(|mode| task::spawn(crowd::run(
+                crowd_rx,
+                mapper_output_tx.clone(),
+                #[cfg(feature = "gui")]
+                crowd_gui_tx.expect("The GUI sender should be cloned for the crowd mode"),
+                mode,
+                args.crowd.clone(),
+            )));
+            let crowd_irc_handle = args.crowd.crowd_irc.clone().map(|address| task::spawn(crowd::run_irc(crowd_tx.clone(), address, args.crowd.clone())));
+            let mapper_handle = task::spawn(mapper::run(controller_rx, mapper_output_tx, args.mapper));
+            #[cfg(feature = "gui")]
+            let gui_tap_handle = task::spawn(input_log::tap_controller(gui_input_rx, live_controller_tx(), input_log.clone()));
+            let web_tap_handle = task::spawn(input_log::tap_controller(web_input_rx, live_controller_tx(), input_log.clone()));
+            let lighthouse_tap_handle = task::spawn(input_log::tap_controller(lighthouse_input_rx, live_controller_tx(), input_log));
+            let queue_handle = args.queue.queue.then(|| task::spawn(queue::run(queue_rx, lighthouse_input_tx.clone(), web_input_tx.clone(), local_input, handover_tx, queue_state_tx, args.queue.clone())));
+            let web_handle = web_listener.map(|listener| {
+                let bridge_target = if args.queue.queue {
+                    BridgeTarget::Queue(queue_tx.clone())
+                } else {
+                    BridgeTarget::Mapper(web_input_tx)
+                };
+                let mut router = bridge::routes(bridge_target);
+                if args.queue.queue {
+                    router = router.merge(queue::routes(queue_tx.clone(), queue_state_rx, &args.queue));
+                }
+                if args.crowd.crowd.is_some() {
+                    router = router.merge(crowd::routes(crowd_tx));
+                }
+                if args.preview.preview {
+                    router = router.merge(preview::routes(preview_frame_rx, args.preview.clone()));
+                }
+                task::spawn(web::run(router, listener))
             });
-        })?
-    };
+            let controller_handle = if args.queue.queue {
+                task::spawn(controller::run_queued(input, queue_tx))
+            } else {
+                task::spawn(controller::run(input, lighthouse_input_tx))
+            };
+
+            updater_handle.await.unwrap().unwrap();
+            for output_handle in output_handles {
+                output_handle.await.unwrap();
+            }
+            if let Some(preview_handle) = preview_handle {
+                preview_handle.await.unwrap().unwrap();
+            }
+            screenshot_handle.await.unwrap().unwrap();
+            if let Some(input_log_handle) = input_log_handle {
+                input_log_handle.await.unwrap().unwrap();
+            }
+            console_handle.await.unwrap().unwrap();
+            mapper_tap_handle.await.unwrap().unwrap();
+            if let Some(crowd_handle) = crowd_handle {
+                crowd_handle.await.unwrap().unwrap();
+            }
+            if let Some(crowd_irc_handle) = crowd_irc_handle {
+                crowd_irc_handle.await.unwrap().unwrap();
+            }
+            mapper_handle.await.unwrap().unwrap();
+            #[cfg(feature = "gui")]
+            gui_tap_handle.await.unwrap().unwrap();
+            web_tap_handle.await.unwrap().unwrap();
+            lighthouse_tap_handle.await.unwrap().unwrap();
+            if let Some(queue_handle) = queue_handle {
+                queue_handle.await.unwrap().unwrap();
+            }
+            if let Some(web_handle) = web_handle {
+                web_handle.await.unwrap().unwrap();
+            }
+            controller_handle.await.unwrap().unwrap();
+        });
+    })?;
 
     let doom_handle = thread::Builder::new().name("DOOM".into()).spawn(move || {
         info!("Running DOOM...");
@@ -79,7 +359,7 @@ fn main() -> Result<()> {
     {
         // NOTE: The GUI must run on the main thread
         info!("Running GUI...");
-        gui::run(gui_rx, controller_tx).unwrap();
+        gui::run(gui_rx, gui_input_tx, args.gui, args.facade).unwrap();
     }
 
     tokio_handle.join().unwrap();
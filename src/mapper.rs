@@ -1,72 +1,193 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::{HashMap, HashSet}, mem, time::{Duration, Instant}};
 
-use anyhow::Result;
-use lighthouse_client::protocol::Direction;
-use tokio::sync::mpsc;
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use lighthouse_client::protocol::{Direction, Vec2, Zero};
+use tokio::{sync::mpsc, time::{self, MissedTickBehavior}};
+use tracing::info;
 
-use crate::message::{Action, ControllerMessage, GamepadButton, GamepadStick, GamepadTrigger, Key, MapperMessage, MouseButton};
+use crate::{analog::{AnalogSettings, TriggerSettings}, arbiter::{ArbitrationOptions, Arbiter}, controller, message::{Action, Command, ControllerInput, ControllerMessage, GamepadButton, GamepadStick, GamepadTrigger, InputSource, Key, MapperMessage, MouseButton}};
 
-pub async fn run(
-    mut rx: mpsc::Receiver<ControllerMessage>,
-    tx: mpsc::Sender<MapperMessage>,
-) -> Result<()> {
-    let mut active_stick_action: HashMap<GamepadStick, Action> = HashMap::new();
-    let mut active_mouse_buttons: HashSet<MouseButton> = HashSet::new();
-    let mut active_mouse_movement: Option<Action> = None;
+/// How often to check for actions that are held for too long.
+const HOLD_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Settings for how the mapper interprets analog input.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Analog input")]
+pub struct MapperOptions {
+    /// Deadzone, response curve and inversion settings for the left stick.
+    #[arg(long, env = "LIDOOM_LEFT_STICK", default_value = "deadzone=0.1")]
+    pub left_stick: AnalogSettings,
+    /// Deadzone, response curve and inversion settings for the right stick.
+    #[arg(long, env = "LIDOOM_RIGHT_STICK", default_value = "deadzone=0.1")]
+    pub right_stick: AnalogSettings,
+    /// Deadzone, response curve and inversion settings for mouse movement.
+    #[arg(long, env = "LIDOOM_MOUSE", default_value = "axial=0.05:0")]
+    pub mouse: AnalogSettings,
+    /// Actuation threshold and binding for the left trigger.
+    #[arg(long, env = "LIDOOM_LEFT_TRIGGER", default_value = "action=none")]
+    pub left_trigger: TriggerSettings,
+    /// Actuation threshold and binding for the right trigger.
+    #[arg(long, env = "LIDOOM_RIGHT_TRIGGER", default_value = "action=fire")]
+    pub right_trigger: TriggerSettings,
+    #[command(flatten)]
+    pub bindings: BindingOptions,
+    #[command(flatten)]
+    pub arbitration: ArbitrationOptions,
+}
+
+/// Keys and buttons that trigger commands rather than being passed to DOOM.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Bindings")]
+pub struct BindingOptions {
+    /// The key that takes a screenshot, as a KeyboardEvent.code (e.g. PrintScreen or F12).
+    #[arg(long, env = "LIDOOM_SCREENSHOT_KEY", default_value = "PrintScreen", value_parser = parse_key)]
+    pub screenshot_key: Key,
+    /// The gamepad button that takes a screenshot, as an index into the standard gamepad layout (e.g. 8 for select).
+    #[arg(long, env = "LIDOOM_SCREENSHOT_BUTTON", value_parser = parse_gamepad_button)]
+    pub screenshot_button: Option<GamepadButton>,
+}
+
+impl BindingOptions {
+    fn key_command(&self, key: Key) -> Option<Command> {
+        (key == self.screenshot_key).then_some(Command::Screenshot)
+    }
+
+    fn button_command(&self, button: GamepadButton) -> Option<Command> {
+        (Some(button) == self.screenshot_button).then_some(Command::Screenshot)
+    }
+}
+
+impl MapperOptions {
+    fn trigger(&self, trigger: GamepadTrigger) -> &TriggerSettings {
+        match trigger {
+            GamepadTrigger::Left => &self.left_trigger,
+            GamepadTrigger::Right => &self.right_trigger,
+        }
+    }
+
+    fn trigger_for_axis(&self, index: usize) -> Option<GamepadTrigger> {
+        [GamepadTrigger::Left, GamepadTrigger::Right].into_iter()
+            .find(|&trigger| self.trigger(trigger).axis == Some(index))
+    }
+}
+
+/// The state of the mapper for a single input source, so that e.g. the sticks
+/// of two gamepads don't interfere with each other.
+#[derive(Debug, Default)]
+struct SourceState {
+    active_stick_action: HashMap<GamepadStick, Action>,
+    running_sticks: HashSet<GamepadStick>,
+    pressed_triggers: HashSet<GamepadTrigger>,
+    active_mouse_buttons: HashSet<MouseButton>,
+    active_mouse_movement: Option<Action>,
+    pointer_locked: bool,
+}
+
+impl SourceState {
+    /// Whether the message means that the source should release everything it
+    /// holds, e.g. because it disconnected or unlocked the pointer.
+    fn releases(&mut self, message: ControllerMessage) -> bool {
+        match message {
+            ControllerMessage::Release => true,
+            ControllerMessage::Mouse { pointer_locked, .. } => mem::replace(&mut self.pointer_locked, pointer_locked) && !pointer_locked,
+            _ => false,
+        }
+    }
+
+    /// Maps a message from the source to the messages for DOOM.
+    fn map(&mut self, message: ControllerMessage, options: &MapperOptions) -> Vec<MapperMessage> {
+        let mut out = Vec::new();
+
+        // Single axes only matter if they are configured to report a trigger
+        let message = match message {
+            ControllerMessage::GamepadAxis { index, value } => match options.trigger_for_axis(index) {
+                Some(trigger) => ControllerMessage::GamepadTrigger { trigger, value },
+                None => return out,
+            },
+            message => message,
+        };
 
-    while let Some(message) = rx.recv().await {
         match message {
             ControllerMessage::Mouse { movement, button, down, pointer_locked } => {
                 macro_rules! pop_active_movement {
                     () => {
-                        if let Some(action) = active_mouse_movement.take() {
-                            tx.send(MapperMessage::Action { action, down: false }).await?;
+                        if let Some(action) = self.active_mouse_movement.take() {
+                            out.push(MapperMessage::Action { action, down: false });
                         }
                     };
                 }
                 if pointer_locked {
-                    let in_deadzone = movement.x.abs() < 0.05;
+                    // Only horizontal movement turns the camera
+                    let movement = options.mouse.apply(movement);
+                    let in_deadzone = movement.x == 0.0;
                     if in_deadzone {
                         pop_active_movement!();
                     } else {
                         let opt_dir = Direction::approximate_from(movement);
                         let opt_action = opt_dir.and_then(camera_dir_to_action);
                         if let Some(action) = opt_action {
-                            tx.send(MapperMessage::Action { action, down: true }).await?;
-                            if Some(action) != active_mouse_movement {
+                            out.push(MapperMessage::Action { action, down: true });
+                            if Some(action) != self.active_mouse_movement {
                                 pop_active_movement!();
-                                active_mouse_movement = Some(action);
+                                self.active_mouse_movement = Some(action);
                             }
                         }
                     }
                 }
-                if down || active_mouse_buttons.contains(&button) {
-                    tx.send(MapperMessage::Action { action: Action::Fire, down }).await?;
+                if down || self.active_mouse_buttons.contains(&button) {
+                    out.push(MapperMessage::Action { action: Action::Fire, down });
                 }
                 if down {
-                    active_mouse_buttons.insert(button);
+                    self.active_mouse_buttons.insert(button);
                 } else {
-                    active_mouse_buttons.remove(&button);
+                    self.active_mouse_buttons.remove(&button);
                 }
             },
             ControllerMessage::Key { key, down } => {
-                tx.send(MapperMessage::Action { action: key_to_action(key), down }).await?;
+                if let Some(command) = options.bindings.key_command(key) {
+                    if down {
+                        out.push(MapperMessage::Command(command));
+                    }
+                } else if let Some(action) = key_to_action(key) {
+                    out.push(MapperMessage::Action { action, down });
+                }
             },
             ControllerMessage::GamepadButton { button, down } => {
-                if let Some(action) = gamepad_button_to_action(button) {
-                    tx.send(MapperMessage::Action { action, down }).await?;
+                if let Some(command) = options.bindings.button_command(button) {
+                    if down {
+                        out.push(MapperMessage::Command(command));
+                    }
+                } else if let Some(action) = gamepad_button_to_action(button) {
+                    out.push(MapperMessage::Action { action, down });
                 }
             },
             ControllerMessage::GamepadStick { stick, value } => {
                 macro_rules! pop_active_action {
                     () => {
-                        if let Some(action) = active_stick_action.remove(&stick) {
-                            tx.send(MapperMessage::Action { action, down: false }).await?;
+                        if let Some(action) = self.active_stick_action.remove(&stick) {
+                            out.push(MapperMessage::Action { action, down: false });
                         }
                     };
                 }
 
-                let in_deadzone = value.length() < 0.1;
+                let settings = match stick {
+                    GamepadStick::Left => &options.left_stick,
+                    GamepadStick::Right => &options.right_stick,
+                };
+                let value = settings.apply(value);
+
+                let running = settings.is_running(value);
+                if running != self.running_sticks.contains(&stick) {
+                    out.push(MapperMessage::Action { action: Action::Speed, down: running });
+                    if running {
+                        self.running_sticks.insert(stick);
+                    } else {
+                        self.running_sticks.remove(&stick);
+                    }
+                }
+
+                let in_deadzone = value == Vec2::ZERO;
                 if in_deadzone {
                     pop_active_action!();
                 } else {
@@ -77,35 +198,152 @@ pub async fn run(
                     };
 
                     if let Some(action) = opt_action {
-                        tx.send(MapperMessage::Action { action, down: true }).await?;
-                        if Some(action) != active_stick_action.get(&stick).cloned() {
+                        out.push(MapperMessage::Action { action, down: true });
+                        if Some(action) != self.active_stick_action.get(&stick).cloned() {
                             pop_active_action!();
-                            active_stick_action.insert(stick, action);
+                            self.active_stick_action.insert(stick, action);
                         }
                     }
                 }
             },
+            ControllerMessage::GamepadTrigger { trigger, value } => {
+                let settings = options.trigger(trigger);
+                let was_pressed = self.pressed_triggers.contains(&trigger);
+                let pressed = settings.is_pressed(was_pressed, value);
+                if pressed != was_pressed {
+                    if let Some(action) = settings.action {
+                        out.push(MapperMessage::Action { action, down: pressed });
+                    }
+                    if pressed {
+                        self.pressed_triggers.insert(trigger);
+                    } else {
+                        self.pressed_triggers.remove(&trigger);
+                    }
+                }
+            },
+            ControllerMessage::GamepadAxis { .. } | ControllerMessage::Release => {},
+        }
+
+        out
+    }
+}
+
+pub async fn run(
+    mut rx: mpsc::Receiver<ControllerInput>,
+    tx: mpsc::Sender<MapperMessage>,
+    options: MapperOptions,
+) -> Result<()> {
+    let mut sources: HashMap<InputSource, SourceState> = HashMap::new();
+    let mut last_input: HashMap<InputSource, Instant> = HashMap::new();
+    let mut arbiter = Arbiter::new(options.arbitration.clone());
+    let timeout = (options.arbitration.input_timeout > 0).then(|| Duration::from_secs(options.arbitration.input_timeout));
+    let mut interval = time::interval(HOLD_CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let messages = tokio::select! {
+            input = rx.recv() => {
+                let Some(ControllerInput { source, message }) = input else { break };
+                let mut messages = Vec::new();
+                if sources.entry(source.clone()).or_default().releases(message) {
+                    info!("Releasing everything held by {source:?}");
+                    // Start over, e.g. so the mouse turns again once the pointer is locked
+                    sources.remove(&source);
+                    messages.extend(arbiter.release(&source));
+                }
+                if message == ControllerMessage::Release {
+                    // The source may be gone for good (e.g. a closed connection)
+                    last_input.remove(&source);
+                } else {
+                    for message in sources.entry(source.clone()).or_default().map(message, &options) {
+                        messages.extend(arbiter.arbitrate(&source, message));
+                    }
+                    last_input.insert(source, Instant::now());
+                }
+                messages
+            },
+            _ = interval.tick() => {
+                let now = Instant::now();
+                let mut messages = Vec::new();
+                if let Some(timeout) = timeout {
+                    // Forget about stale sources, since e.g. closed browser tabs never say goodbye
+                    last_input.retain(|source, &mut time| {
+                        if now.saturating_duration_since(time) < timeout {
+                            return true;
+                        }
+                        if arbiter.holds_any(source) {
+                            info!("Releasing everything held by {source:?}, which sent no input for {} s", timeout.as_secs());
+                        }
+                        sources.remove(source);
+                        messages.extend(arbiter.release(source));
+                        false
+                    });
+                }
+                messages.extend(arbiter.expire(now));
+                messages
+            },
+        };
+        for message in messages {
+            tx.send(message).await?;
         }
     }
     Ok(())
 }
 
-fn key_to_action(key: Key) -> Action {
+fn parse_key(code: &str) -> Result<Key> {
+    controller::convert_key(code).ok_or_else(|| anyhow!("Unknown key code '{code}'"))
+}
+
+fn parse_gamepad_button(index: &str) -> Result<GamepadButton> {
+    let index: usize = index.parse().with_context(|| format!("Invalid button index '{index}'"))?;
+    controller::convert_gamepad_button(index).ok_or_else(|| anyhow!("Unsupported button index {index}"))
+}
+
+fn key_to_action(key: Key) -> Option<Action> {
     match key {
-        Key::ArrowRight => Action::Right,
-        Key::ArrowLeft => Action::Left,
-        Key::ArrowUp => Action::Up,
-        Key::ArrowDown => Action::Down,
-        Key::Letter('W') => Action::Up,
-        Key::Letter('S') => Action::Down,
-        Key::Letter('A') => Action::StrafeLeft,
-        Key::Letter('D') => Action::StrafeRight,
-        Key::Ctrl => Action::Use,
-        Key::Space => Action::Fire,
-        Key::Shift => Action::Speed,
-        Key::Escape => Action::Escape,
-        Key::Enter => Action::Enter,
-        Key::Letter(c) => Action::KeyLetter(c),
+        Key::ArrowRight => Some(Action::Right),
+        Key::ArrowLeft => Some(Action::Left),
+        Key::ArrowUp => Some(Action::Up),
+        Key::ArrowDown => Some(Action::Down),
+        Key::Letter('W') => Some(Action::Up),
+        Key::Letter('S') => Some(Action::Down),
+        Key::Letter('A') => Some(Action::StrafeLeft),
+        Key::Letter('D') => Some(Action::StrafeRight),
+        Key::Ctrl => Some(Action::Use),
+        Key::Space => Some(Action::Fire),
+        Key::Shift => Some(Action::Speed),
+        Key::Alt => Some(Action::Strafe),
+        Key::Escape => Some(Action::Escape),
+        Key::Enter => Some(Action::Enter),
+        Key::Tab => Some(Action::Tab),
+        Key::Backspace => Some(Action::Backspace),
+        Key::Pause => Some(Action::Pause),
+        Key::Insert => Some(Action::Insert),
+        Key::Delete => Some(Action::Delete),
+        Key::Home => Some(Action::Home),
+        Key::End => Some(Action::End),
+        Key::PageUp => Some(Action::PageUp),
+        Key::PageDown => Some(Action::PageDown),
+        Key::CapsLock => Some(Action::CapsLock),
+        Key::NumLock => Some(Action::NumLock),
+        Key::ScrollLock => Some(Action::ScrollLock),
+        Key::PrintScreen => Some(Action::PrintScreen),
+        Key::Function(n) => Some(Action::Function(n)),
+        Key::Meta | Key::ContextMenu => None,
+        Key::Letter(c) | Key::Digit(c) | Key::Symbol(c) => Some(Action::KeyLetter(c)),
+        // Mirrors DOOM's own keypad mapping (see KEYP_* in doomkeys.h)
+        Key::Numpad(c) => match c {
+            '8' => Some(Action::Up),
+            '2' => Some(Action::Down),
+            '4' => Some(Action::Left),
+            '6' => Some(Action::Right),
+            '7' => Some(Action::Home),
+            '1' => Some(Action::End),
+            '9' => Some(Action::PageUp),
+            '3' => Some(Action::PageDown),
+            '5' | '+' | '-' | '*' | '/' | '=' => Some(Action::KeyLetter(c)),
+            _ => None,
+        },
     }
 }
 
@@ -119,10 +357,7 @@ fn gamepad_button_to_action(button: GamepadButton) -> Option<Action> {
             Direction::Right => Some(Action::Use),
             _ => None,
         },
-        GamepadButton::Trigger(trigger) => match trigger {
-            GamepadTrigger::Right => Some(Action::Fire),
-            _ => None,
-        },
+        GamepadButton::Select | GamepadButton::Home | GamepadButton::Bumper(_) | GamepadButton::Stick(_) => None,
     }
 }
 
@@ -1,7 +1,14 @@
-use lighthouse_client::protocol::{Delta, Direction, Frame, Vec2};
+use std::time::Instant;
+
+use clap::ValueEnum;
+use lighthouse_client::protocol::{Delta, Direction, Vec2};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{framebuffer::SharedFrame, state::GameState};
 
 /// A button on the mouse.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MouseButton {
     Left,
     Middle,
@@ -9,8 +16,7 @@ pub enum MouseButton {
 }
 
 /// A key on the keyboard.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Key {
     ArrowRight,
     ArrowLeft,
@@ -21,34 +27,65 @@ pub enum Key {
     Shift,
     Space,
     Ctrl,
+    Alt,
+    Meta,
+    Tab,
+    Backspace,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    PrintScreen,
+    Pause,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    ContextMenu,
+    /// A function key, e.g. `Function(1)` for F1.
+    Function(u8),
+    /// A letter key, always uppercase.
     Letter(char),
+    /// A digit key on the main keyboard.
+    Digit(char),
+    /// A punctuation key on the main keyboard, as typed on a US layout
+    /// without shift.
+    Symbol(char),
+    /// A key on the numpad, e.g. `Numpad('5')` or `Numpad('+')`.
+    Numpad(char),
 }
 
 /// A trigger on the gamepad.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum GamepadTrigger {
     Left,
     Right,
 }
 
 /// A button on the gamepad.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum GamepadButton {
     DPad(Direction),
     Menu,
+    Select,
+    Home,
     Cluster(Direction),
-    Trigger(GamepadTrigger),
+    /// A shoulder button, either `Left` or `Right`.
+    Bumper(Direction),
+    /// Pressing down a stick.
+    Stick(GamepadStick),
 }
 
 /// A stick on the gamepad.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum GamepadStick {
     Left,
     Right,
 }
 
 /// A game action to take. Usually this is what keys are mapped to.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Action {
     Right,
     Left,
@@ -61,34 +98,165 @@ pub enum Action {
     Use,
     Fire,
     Speed,
+    Strafe,
+    Tab,
+    Backspace,
+    Pause,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    PrintScreen,
+    Function(u8),
     KeyLetter(char),
 }
 
+/// A command that is not passed to DOOM as a key, e.g. from a binding or the
+/// console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Command {
+    Screenshot,
+    RecordDemo,
+    StopDemo,
+    Attract,
+}
+
 /// A message sent from controller or gui -> mapper.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ControllerMessage {
     Mouse { button: MouseButton, movement: Delta<f64>, down: bool, pointer_locked: bool },
     Key { key: Key, down: bool },
     GamepadButton { button: GamepadButton, down: bool },
     GamepadStick { stick: GamepadStick, value: Vec2<f64> },
+    GamepadTrigger { trigger: GamepadTrigger, value: f64 },
+    GamepadAxis { index: usize, value: f64 },
+    /// Releases everything the source holds, e.g. because it disconnected or
+    /// lost focus.
+    Release,
+}
+
+/// Where a `ControllerMessage` came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputSource {
+    /// A client of the Lighthouse frontend, identified by the source of its
+    /// input events.
+    Lighthouse(String),
+    Gui,
+    /// A connection to the WebSocket bridge, identified by its ticket.
+    #[serde(rename = "websocket")]
+    WebSocket(u64),
+}
+
+impl InputSource {
+    pub fn kind(&self) -> InputSourceKind {
+        match self {
+            Self::Lighthouse(_) => InputSourceKind::Lighthouse,
+            Self::Gui => InputSourceKind::Gui,
+            Self::WebSocket(_) => InputSourceKind::WebSocket,
+        }
+    }
 }
 
-/// A message sent from mapper -> doom.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The kind of an `InputSource`, regardless of which client it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum InputSourceKind {
+    Lighthouse,
+    Gui,
+    #[value(name = "websocket")]
+    WebSocket,
+}
+
+/// A message sent from gui, controller, web or queue -> mapper (through the
+/// input taps), tagged with its source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerInput {
+    pub source: InputSource,
+    pub message: ControllerMessage,
+}
+
+/// A message sent from mapper or console -> doom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MapperMessage {
     Action { action: Action, down: bool },
+    Command(Command),
 }
 
-/// A message sent from doom -> updater.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A player in the queue.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PlayerId {
+    /// A client of the Lighthouse frontend, identified by the source of its
+    /// input events.
+    Lighthouse(String),
+    /// A visitor who joined via the web page with the given ticket and plays
+    /// with the local controls or via the WebSocket bridge.
+    Web(u64),
+}
+
+/// A message sent from controller or web -> queue.
+#[derive(Debug)]
+pub enum QueueMessage {
+    /// Joins the queue, replying with the player's number.
+    Join { player: PlayerId, reply: oneshot::Sender<u64> },
+    Leave { player: PlayerId },
+    Input { player: PlayerId, message: ControllerMessage },
+}
+
+/// A message sent from queue -> doom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandoverMessage {
+    /// Hands the game over to the next player.
+    Start { previous: Option<PlayerId>, player: PlayerId, number: u64, ends_at: Instant },
+    /// Extends the current turn, since nobody is waiting.
+    Extend { ends_at: Instant },
+    /// Ends the current turn without anyone waiting.
+    End { previous: PlayerId },
+}
+
+/// A message sent from irc or web -> crowd.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrowdMessage {
+    Vote { voter: String, action: Action },
+}
+
+/// The votes of the last window in crowd mode, along with its winner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub struct VoteTally {
+    /// The actions voted for and their votes, with the most popular first.
+    pub votes: Vec<(Action, usize)>,
+    pub winner: Option<Action>,
+}
+
+/// A message sent from doom (or replay) -> updater, recorder.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UpdaterMessage {
-    Frame(Frame),
+    Frame(SharedFrame),
 }
 
-/// A message sent from updater -> gui.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-#[allow(dead_code)]
+/// A message sent from doom -> updater.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateMessage {
+    Publish(GameState),
+}
+
+/// A message sent from doom -> screenshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScreenshotMessage {
+    Capture(SharedFrame),
+}
+
+/// A message sent from doom -> gui.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
 pub enum GUIMessage {
-    Frame(Vec<u8>),
+    Frame(SharedFrame),
     UpdateTitle(String),
+    Rumble { strength: u16, duration_ms: u32 },
+    Vote(VoteTally),
 }
@@ -0,0 +1,234 @@
+//! Text that is drawn on top of DOOM's frames, e.g. hints in attract mode.
+//!
+//! Since the lighthouse only has 28x14 windows, the text is set in a tiny 3x5
+//! pixel font, which is scaled up for the full-resolution image. Text that
+//! doesn't fit onto the lighthouse scrolls by as a marquee.
+
+use std::time::Instant;
+
+use lighthouse_client::protocol::{Color, Frame, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+
+use crate::constants::{DOOM_HEIGHT, DOOM_WIDTH};
+
+const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+/// The horizontal distance between the starts of consecutive glyphs.
+pub const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1;
+/// The factor by which the font is scaled up in the full-resolution image.
+const DOOM_SCALE: usize = 8;
+/// The padding around the text in the full-resolution image.
+const DOOM_PADDING: usize = 12;
+/// How fast text scrolls across the lighthouse, in windows per second.
+const MARQUEE_SPEED: f64 = 8.0;
+const TEXT_COLOR: Color = Color::new(255, 200, 0);
+
+/// A line of text that is drawn at the bottom of the frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlay {
+    text: String,
+    since: Instant,
+}
+
+impl Overlay {
+    /// Creates an overlay with the given text. Characters that the font
+    /// doesn't support are drawn as spaces.
+    pub fn new(text: impl Into<String>) -> Self {
+        Self { text: text.into().to_ascii_uppercase(), since: Instant::now() }
+    }
+
+    /// The text, in uppercase.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The width of the text in font pixels.
+    fn width(&self) -> usize {
+        (self.text.chars().count() * GLYPH_ADVANCE).saturating_sub(1)
+    }
+
+    /// Draws the text onto DOOM's pixels (in XRGB8888) and the lighthouse
+    /// frame.
+    pub fn draw(&self, pixels: &mut [u32], lighthouse: &mut Frame) {
+        self.draw_doom(pixels);
+        self.draw_lighthouse(lighthouse);
+    }
+
+    fn draw_doom(&self, pixels: &mut [u32]) {
+        let width = (self.width() * DOOM_SCALE).min(DOOM_WIDTH - 2 * DOOM_PADDING);
+        let height = GLYPH_HEIGHT * DOOM_SCALE;
+        let left = (DOOM_WIDTH - width) / 2;
+        let top = DOOM_HEIGHT - height - 3 * DOOM_PADDING;
+
+        // Darken the background to keep the text readable
+        for y in (top - DOOM_PADDING)..(top + height + DOOM_PADDING) {
+            for x in (left - DOOM_PADDING)..(left + width + DOOM_PADDING) {
+                let pixel = &mut pixels[y * DOOM_WIDTH + x];
+                *pixel = (*pixel >> 2) & 0x3F3F3F;
+            }
+        }
+
+        let color = ((TEXT_COLOR.red as u32) << 16) | ((TEXT_COLOR.green as u32) << 8) | TEXT_COLOR.blue as u32;
+        draw_text(&self.text, |x, y| {
+            for dy in 0..DOOM_SCALE {
+                for dx in 0..DOOM_SCALE {
+                    let (x, y) = (left + x * DOOM_SCALE + dx, top + y * DOOM_SCALE + dy);
+                    if x < left + width {
+                        pixels[y * DOOM_WIDTH + x] = color;
+                    }
+                }
+            }
+        });
+    }
+
+    fn draw_lighthouse(&self, lighthouse: &mut Frame) {
+        let top = LIGHTHOUSE_ROWS - GLYPH_HEIGHT;
+        let width = self.width();
+
+        // Center the text if it fits, otherwise scroll it in from the right
+        let offset = if width <= LIGHTHOUSE_COLS {
+            ((LIGHTHOUSE_COLS - width) / 2) as isize
+        } else {
+            let period = LIGHTHOUSE_COLS + width;
+            let scrolled = (self.since.elapsed().as_secs_f64() * MARQUEE_SPEED) as usize % period;
+            LIGHTHOUSE_COLS as isize - scrolled as isize
+        };
+
+        for y in top..LIGHTHOUSE_ROWS {
+            for x in 0..LIGHTHOUSE_COLS {
+                lighthouse.set(x, y, Color::BLACK);
+            }
+        }
+        draw_text(&self.text, |x, y| {
+            let x = x as isize + offset;
+            if (0..LIGHTHOUSE_COLS as isize).contains(&x) {
+                lighthouse.set(x as usize, top + y, TEXT_COLOR);
+            }
+        });
+    }
+}
+
+/// Calls `set` with the position (in font pixels) of every pixel of the text,
+/// which should be in uppercase.
+pub fn draw_text(text: &str, mut set: impl FnMut(usize, usize)) {
+    for (i, c) in text.chars().enumerate() {
+        for (y, row) in glyph(c).into_iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if row & (0b100 >> x) != 0 {
+                    set(i * GLYPH_ADVANCE + x, y);
+                }
+            }
+        }
+    }
+}
+
+/// The rows of the glyph for the given character, with the most significant
+/// of the lower three bits being the leftmost pixel.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '?' => [0b110, 0b001, 0b010, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use lighthouse_client::protocol::{Color, Frame, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+
+    use crate::constants::{DOOM_HEIGHT, DOOM_WIDTH};
+
+    use super::{draw_text, Overlay, GLYPH_ADVANCE, GLYPH_HEIGHT, TEXT_COLOR};
+
+    fn lit_columns(frame: &Frame) -> Vec<usize> {
+        (0..LIGHTHOUSE_COLS)
+            .filter(|&x| (LIGHTHOUSE_ROWS - GLYPH_HEIGHT..LIGHTHOUSE_ROWS).any(|y| frame.get(x, y) == TEXT_COLOR))
+            .collect()
+    }
+
+    #[test]
+    fn layout() {
+        let overlay = Overlay::new("Hi!");
+        assert_eq!(overlay.text(), "HI!");
+        assert_eq!(overlay.width(), 3 * GLYPH_ADVANCE - 1);
+        assert_eq!(Overlay::new("").width(), 0);
+
+        let mut pixels = Vec::new();
+        draw_text("1 -", |x, y| pixels.push((x, y)));
+        assert_eq!(pixels, [(1, 0), (0, 1), (1, 1), (1, 2), (1, 3), (0, 4), (1, 4), (2, 4), (8, 2), (9, 2), (10, 2)]);
+    }
+
+    #[test]
+    fn centered() {
+        let mut frame = Frame::fill(Color::WHITE);
+        Overlay::new("II").draw_lighthouse(&mut frame);
+        // 7 pixels wide, so 10 columns are left on either side
+        assert_eq!(lit_columns(&frame), [10, 11, 12, 14, 15, 16]);
+        assert_eq!(frame.get(0, LIGHTHOUSE_ROWS - 1), Color::BLACK);
+        assert_eq!(frame.get(0, LIGHTHOUSE_ROWS - GLYPH_HEIGHT - 1), Color::WHITE);
+    }
+
+    #[test]
+    fn marquee() {
+        let text = "I".repeat(10);
+        let mut overlay = Overlay::new(text);
+        let mut frame = Frame::empty();
+        overlay.draw_lighthouse(&mut frame);
+        assert!(lit_columns(&frame).is_empty());
+
+        // After a second, the text has scrolled in by 8 windows
+        overlay.since = Instant::now() - Duration::from_millis(1050);
+        overlay.draw_lighthouse(&mut frame);
+        assert_eq!(lit_columns(&frame), [20, 21, 22, 24, 25, 26]);
+    }
+
+    #[test]
+    fn long_text_fits_doom() {
+        let mut pixels = vec![0; DOOM_WIDTH * DOOM_HEIGHT];
+        Overlay::new("W".repeat(100)).draw(&mut pixels, &mut Frame::empty());
+        assert!(pixels.iter().any(|&p| p != 0));
+    }
+}
@@ -0,0 +1,115 @@
+//! A live preview of the game in the browser, e.g. for watching a session from
+//! a laptop without the GUI or LUNA.
+//!
+//! The page at `/preview` connects to the WebSocket at `/preview/stream`,
+//! which first sends a JSON header with the format and size of the frames,
+//! followed by one binary message per frame. Lighthouse frames are sent as raw
+//! RGB24, full-resolution frames (see `--preview-full`) as PNG images.
+
+use std::{io::Cursor, time::Duration};
+
+use anyhow::Result;
+use axum::{extract::{ws::{Message, WebSocket}, Query, State, WebSocketUpgrade}, response::{Html, Response}, routing::get, Router};
+use clap::Args;
+use lighthouse_client::protocol::{LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{sync::{mpsc, watch}, task, time::{self, MissedTickBehavior}};
+use tracing::{debug, info};
+
+use crate::{constants::{DOOM_HEIGHT, DOOM_WIDTH}, framebuffer::{FrameBuffer, SharedFrame}, message::UpdaterMessage};
+
+/// Settings for the browser preview.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Preview")]
+pub struct PreviewOptions {
+    /// Serves a live preview of the lighthouse frames at /preview (see --web).
+    #[arg(long, env = "LIDOOM_PREVIEW", requires = "web")]
+    pub preview: bool,
+    /// Lets the preview also stream DOOM's full resolution.
+    #[arg(long, env = "LIDOOM_PREVIEW_FULL", requires = "preview")]
+    pub preview_full: bool,
+    /// The maximum frame rate of the full-resolution stream, which is much larger than the lighthouse frames.
+    #[arg(long, env = "LIDOOM_PREVIEW_FULL_FPS", default_value_t = 10.0)]
+    pub preview_full_fps: f64,
+}
+
+/// Keeps the latest frame for the preview's clients.
+pub async fn run(mut rx: mpsc::Receiver<UpdaterMessage>, tx: watch::Sender<Option<SharedFrame>>) -> Result<()> {
+    while let Some(UpdaterMessage::Frame(frame)) = rx.recv().await {
+        tx.send_replace(Some(frame));
+    }
+    Ok(())
+}
+
+/// The state shared by the preview's handlers.
+#[derive(Debug, Clone)]
+struct PreviewState {
+    frame_rx: watch::Receiver<Option<SharedFrame>>,
+    options: PreviewOptions,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct StreamQuery {
+    #[serde(default)]
+    full: bool,
+}
+
+/// The routes for the preview page and its stream.
+pub fn routes(frame_rx: watch::Receiver<Option<SharedFrame>>, options: PreviewOptions) -> Router {
+    Router::new()
+        .route("/preview", get(|| async { Html(include_str!("../static/preview.html")) }))
+        .route("/preview/stream", get(connect))
+        .with_state(PreviewState { frame_rx, options })
+}
+
+async fn connect(State(state): State<PreviewState>, Query(query): Query<StreamQuery>, upgrade: WebSocketUpgrade) -> Response {
+    // Fall back to the lighthouse frames if the full resolution isn't enabled
+    let full = query.full && state.options.preview_full;
+    upgrade.on_upgrade(move |socket| async move {
+        info!("Preview client connected");
+        if let Err(e) = send_frames(socket, state.frame_rx, full, state.options.preview_full_fps).await {
+            debug!("Stopped streaming the preview: {e:#}");
+        }
+        info!("Preview client disconnected");
+    })
+}
+
+async fn send_frames(mut socket: WebSocket, mut frame_rx: watch::Receiver<Option<SharedFrame>>, full: bool, full_fps: f64) -> Result<()> {
+    let header = if full {
+        json!({ "format": "png", "width": DOOM_WIDTH, "height": DOOM_HEIGHT })
+    } else {
+        json!({ "format": "rgb", "width": LIGHTHOUSE_COLS, "height": LIGHTHOUSE_ROWS })
+    };
+    socket.send(Message::Text(header.to_string().into())).await?;
+
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / full_fps.max(1.0)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    frame_rx.mark_changed();
+
+    loop {
+        if full {
+            interval.tick().await;
+        }
+        frame_rx.changed().await?;
+        let Some(frame) = frame_rx.borrow_and_update().clone() else { continue };
+        let bytes = if full {
+            // Encoding is CPU-bound, so we'll do it off the async threads
+            task::spawn_blocking(move || encode_png(&frame)).await??
+        } else {
+            Vec::from(*frame.lighthouse())
+        };
+        socket.send(Message::Binary(bytes.into())).await?;
+    }
+}
+
+/// Encodes the full DOOM image as a PNG.
+fn encode_png(frame: &FrameBuffer) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(Cursor::new(&mut bytes), DOOM_WIDTH as u32, DOOM_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Fast);
+    encoder.write_header()?.write_image_data(&frame.rgb())?;
+    Ok(bytes)
+}
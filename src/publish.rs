@@ -0,0 +1,73 @@
+//! Publishing the game state to a resource next to the user's model, e.g. for
+//! companion apps that show scoreboards and stats next to the building.
+//!
+//! The state is read on the DOOM thread (see `state`) and sent to the updater,
+//! which posts it as a MessagePack document to `/user/<username>/<resource>`.
+
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use crate::{message::StateMessage, state::{self, GameState}};
+
+/// Settings for publishing the game state.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Game state")]
+pub struct PublishOptions {
+    /// The name of a resource in the user's directory to publish the game state to, e.g. doom-state (disabled by default).
+    #[arg(long, env = "LIDOOM_PUBLISH_STATE")]
+    pub publish_state: Option<String>,
+    /// How often to publish the game state in milliseconds (if it changed).
+    #[arg(long, env = "LIDOOM_PUBLISH_STATE_INTERVAL", default_value_t = 1000)]
+    pub publish_state_interval: u64,
+}
+
+/// Reads the game state every now and then and sends it to the updater if it
+/// changed. This lives on the DOOM thread.
+#[derive(Debug)]
+pub struct StatePublisher {
+    tx: Option<mpsc::Sender<StateMessage>>,
+    interval: Duration,
+    last_update: Option<Instant>,
+    /// The last state that made it to the updater.
+    published: Option<GameState>,
+}
+
+impl StatePublisher {
+    /// Creates a publisher that sends to the given channel or, if `None`,
+    /// doesn't publish anything.
+    pub fn new(tx: Option<mpsc::Sender<StateMessage>>, options: &PublishOptions) -> Self {
+        Self {
+            tx,
+            interval: Duration::from_millis(options.publish_state_interval),
+            last_update: None,
+            published: None,
+        }
+    }
+
+    /// Publishes the game state if it's time to. Should be called once per
+    /// frame with the number of the current player, if any.
+    pub fn update(&mut self, player: Option<u64>) {
+        let Some(tx) = &self.tx else { return };
+        if self.last_update.is_some_and(|time| time.elapsed() < self.interval) {
+            return;
+        }
+        self.last_update = Some(Instant::now());
+        let game_state = GameState { player, ..state::game_state() };
+        if self.published.as_ref() == Some(&game_state) {
+            return;
+        }
+        // Don't stall the game if the lighthouse can't keep up, we'll try again
+        // with the then current state once the interval has passed
+        match tx.try_send(StateMessage::Publish(game_state.clone())) {
+            Ok(()) => self.published = Some(game_state),
+            Err(TrySendError::Full(_)) => {},
+            Err(TrySendError::Closed(_)) => {
+                warn!("Stopped publishing the game state, the updater is gone");
+                self.tx = None;
+            },
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! A queue of players who take turns, e.g. when many visitors want to play on
+//! the building at once.
+//!
+//! Players join either by pressing a key in the Lighthouse frontend or via the
+//! web page, which hands out tickets for playing with the local controls (i.e.
+//! the GUI) or via the WebSocket bridge (see `bridge`). During a player's turn,
+//! only their input is passed on to the mapper, while `handover` hands over the
+//! game on the DOOM thread.
+
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use axum::{extract::{Query, State}, http::StatusCode, response::{Html, Redirect}, routing::{get, post}, Json, Router};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::{mpsc, oneshot, watch}, time::{self, Instant}};
+use tracing::{info, warn};
+
+use crate::message::{ControllerInput, ControllerMessage, HandoverMessage, InputSource, PlayerId, QueueMessage};
+
+/// Settings for the player queue.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Player queue")]
+pub struct QueueOptions {
+    /// Whether players take turns, joining by pressing a key in the Lighthouse frontend or via the web page (see --web).
+    #[arg(long, env = "LIDOOM_QUEUE")]
+    pub queue: bool,
+    /// The length of a turn in seconds. Turns are extended while nobody is waiting.
+    #[arg(long, env = "LIDOOM_QUEUE_TURN", default_value_t = 180)]
+    pub queue_turn: u64,
+    /// The number of seconds without input after which a turn ends early, or 0 to always play the full turn.
+    #[arg(long, env = "LIDOOM_QUEUE_IDLE", default_value_t = 30)]
+    pub queue_idle: u64,
+    /// How the game is handed over to the next player.
+    #[arg(long, env = "LIDOOM_QUEUE_HANDOVER", default_value = "new-game")]
+    pub queue_handover: HandoverMode,
+    /// The directory to keep the players' games in with --queue-handover swap.
+    #[arg(long, env = "LIDOOM_QUEUE_SAVE_DIR", default_value = "saves")]
+    pub queue_save_dir: PathBuf,
+}
+
+/// How the game is handed over to the next player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HandoverMode {
+    /// Start a new game for every turn.
+    NewGame,
+    /// Save the game at the end of a turn and resume it on the player's next turn.
+    Swap,
+}
+
+/// A player waiting in the queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct QueueEntry {
+    player: PlayerId,
+    /// The number that the player is shown as, counting up from 1.
+    number: u64,
+    /// Whether the player sends their own input (e.g. via the Lighthouse
+    /// frontend or the WebSocket bridge) rather than playing with the local
+    /// controls.
+    remote: bool,
+}
+
+/// The player whose turn it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Turn {
+    entry: QueueEntry,
+    ends_at: Instant,
+    last_input: Instant,
+}
+
+/// The players in the queue.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Queue {
+    current: Option<Turn>,
+    waiting: VecDeque<QueueEntry>,
+    last_number: u64,
+}
+
+impl Queue {
+    fn is_current(&self, player: &PlayerId) -> bool {
+        self.current.as_ref().is_some_and(|turn| &turn.entry.player == player)
+    }
+
+    fn find_mut(&mut self, player: &PlayerId) -> Option<&mut QueueEntry> {
+        self.current.iter_mut().map(|turn| &mut turn.entry)
+            .chain(&mut self.waiting)
+            .find(|entry| &entry.player == player)
+    }
+
+    /// Adds the player to the end of the queue, unless they already are in it,
+    /// returning their number.
+    fn join(&mut self, player: PlayerId, remote: bool) -> u64 {
+        if let Some(entry) = self.find_mut(&player) {
+            // Players from the web page may connect via the bridge later on
+            entry.remote |= remote;
+            return entry.number;
+        }
+        self.last_number += 1;
+        info!("Player {} joined the queue ({:?})", self.last_number, player);
+        self.waiting.push_back(QueueEntry { player, number: self.last_number, remote });
+        self.last_number
+    }
+
+    /// Removes the player from the queue, returning their entry if it was their
+    /// turn.
+    fn leave(&mut self, player: &PlayerId) -> Option<QueueEntry> {
+        self.waiting.retain(|entry| &entry.player != player);
+        if self.is_current(player) {
+            self.current.take().map(|turn| turn.entry)
+        } else {
+            None
+        }
+    }
+
+    /// When the current turn ends, either due to its length or inactivity.
+    fn deadline(&self, idle: Option<Duration>) -> Option<Instant> {
+        self.current.as_ref().map(|turn| match idle {
+            Some(idle) => turn.ends_at.min(turn.last_input + idle),
+            None => turn.ends_at,
+        })
+    }
+
+    /// Starts the next turn after the previous player's, if anyone is waiting.
+    fn advance(&mut self, previous: Option<PlayerId>, length: Duration) -> Option<HandoverMessage> {
+        let Some(entry) = self.waiting.pop_front() else {
+            return previous.map(|previous| HandoverMessage::End { previous });
+        };
+        info!("Handing over to player {}", entry.number);
+        let now = Instant::now();
+        let ends_at = now + length;
+        let message = HandoverMessage::Start {
+            previous,
+            player: entry.player.clone(),
+            number: entry.number,
+            ends_at: ends_at.into_std(),
+        };
+        self.current = Some(Turn { entry, ends_at, last_input: now });
+        Some(message)
+    }
+}
+
+/// Whether the message is a deliberate press (rather than e.g. a stick
+/// drifting), which is what it takes to join the queue.
+fn is_press(message: &ControllerMessage) -> bool {
+    matches!(
+        message,
+        ControllerMessage::Key { down: true, .. }
+            | ControllerMessage::Mouse { down: true, .. }
+            | ControllerMessage::GamepadButton { down: true, .. }
+    )
+}
+
+/// Runs the queue, passing the current player's input on to `lighthouse_tx` or
+/// `web_tx`, depending on where they joined. Input from the local controls is
+/// passed on whenever it's the turn of a player from the web page who doesn't
+/// send their own input or nobody's turn at all.
+pub async fn run(
+    mut rx: mpsc::Receiver<QueueMessage>,
+    lighthouse_tx: mpsc::Sender<ControllerInput>,
+    web_tx: mpsc::Sender<ControllerInput>,
+    local: Option<(mpsc::Receiver<ControllerInput>, mpsc::Sender<ControllerInput>)>,
+    handover_tx: mpsc::Sender<HandoverMessage>,
+    queue_tx: watch::Sender<Queue>,
+    options: QueueOptions,
+) -> Result<()> {
+    let length = Duration::from_secs(options.queue_turn);
+    let idle = (options.queue_idle > 0).then(|| Duration::from_secs(options.queue_idle));
+    let (mut local_rx, local_tx) = local.unzip();
+    let mut queue = Queue::default();
+
+    loop {
+        let deadline = queue.deadline(idle);
+        // The previous player, if their turn ended
+        let mut ended = None;
+
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(QueueMessage::Join { player, reply }) => {
+                    // The web page may have given up waiting already
+                    _ = reply.send(queue.join(player, false));
+                },
+                Some(QueueMessage::Leave { player }) => if let Some(entry) = queue.leave(&player) {
+                    info!("Player left during their turn ({:?})", player);
+                    ended = Some(entry);
+                },
+                Some(QueueMessage::Input { player, message }) => {
+                    if let Some(turn) = queue.current.as_mut().filter(|turn| turn.entry.player == player) {
+                        turn.last_input = Instant::now();
+                        turn.entry.remote = true;
+                        let tx = match player {
+                            PlayerId::Lighthouse(_) => &lighthouse_tx,
+                            PlayerId::Web(_) => &web_tx,
+                        };
+                        tx.send(ControllerInput { source: input_source(&player), message }).await?;
+                    } else if let Some(entry) = queue.find_mut(&player) {
+                        entry.remote = true;
+                    } else if is_press(&message) {
+                        queue.join(player, true);
+                    }
+                },
+                None => break,
+            },
+            input = async { local_rx.as_mut().unwrap().recv().await }, if local_rx.is_some() => match input {
+                Some(input) => {
+                    let is_local_turn = match &mut queue.current {
+                        Some(turn) if !turn.entry.remote => {
+                            turn.last_input = Instant::now();
+                            true
+                        },
+                        Some(_) => false,
+                        None => true,
+                    };
+                    if let (true, Some(local_tx)) = (is_local_turn, &local_tx) {
+                        local_tx.send(input).await?;
+                    }
+                },
+                None => local_rx = None,
+            },
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let turn = queue.current.as_mut().unwrap();
+                let now = Instant::now();
+                if now < turn.ends_at {
+                    info!("Ending the turn of player {} due to inactivity", turn.entry.number);
+                    ended = queue.current.take().map(|turn| turn.entry);
+                } else if queue.waiting.is_empty() {
+                    turn.ends_at = now + length;
+                    handover_tx.send(HandoverMessage::Extend { ends_at: turn.ends_at.into_std() }).await?;
+                } else {
+                    info!("The turn of player {} is over", turn.entry.number);
+                    ended = queue.current.take().map(|turn| turn.entry);
+                }
+            },
+        }
+
+        if let Some(QueueEntry { player, remote, .. }) = &ended {
+            // Whatever the previous player still holds must not carry over to the next one
+            let release = ControllerInput { source: input_source(player), message: ControllerMessage::Release };
+            match player {
+                PlayerId::Lighthouse(_) => lighthouse_tx.send(release).await?,
+                PlayerId::Web(_) => web_tx.send(release).await?,
+            }
+            if let (false, Some(local_tx)) = (*remote, &local_tx) {
+                local_tx.send(ControllerInput { source: InputSource::Gui, message: ControllerMessage::Release }).await?;
+            }
+        }
+
+        if queue.current.is_none() {
+            if let Some(message) = queue.advance(ended.map(|entry| entry.player), length) {
+                handover_tx.send(message).await?;
+            }
+        }
+        queue_tx.send_replace(queue.clone());
+    }
+
+    Ok(())
+}
+
+/// The source that a player's input is passed on to the mapper as.
+fn input_source(player: &PlayerId) -> InputSource {
+    match player {
+        PlayerId::Lighthouse(client) => InputSource::Lighthouse(client.clone()),
+        PlayerId::Web(ticket) => InputSource::WebSocket(*ticket),
+    }
+}
+
+/// The state shared by the web page's handlers.
+#[derive(Debug, Clone)]
+struct WebState {
+    tx: mpsc::Sender<QueueMessage>,
+    queue_rx: watch::Receiver<Queue>,
+    length: Duration,
+}
+
+/// A ticket for the web page, which identifies a player.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Ticket {
+    ticket: u64,
+}
+
+/// A player's place in the queue, as polled by the web page.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum TicketStatus {
+    Waiting { number: u64, ahead: usize, wait_secs: u64 },
+    Playing { number: u64, remaining_secs: u64 },
+    /// The ticket is not (or no longer) in the queue.
+    Expired,
+}
+
+/// Creates a ticket for a player from the web page.
+pub fn new_ticket() -> Result<u64> {
+    // Tickets come from the OS's secure random source, so visitors can't guess
+    // them to leave the queue on behalf of others, and fit into the 53 bits
+    // that JavaScript's numbers represent exactly
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes) >> 11)
+}
+
+/// The routes for joining the queue via the web page.
+pub fn routes(tx: mpsc::Sender<QueueMessage>, queue_rx: watch::Receiver<Queue>, options: &QueueOptions) -> Router {
+    let state = WebState { tx, queue_rx, length: Duration::from_secs(options.queue_turn) };
+    Router::new()
+        .route("/", get(|| async { Redirect::to("/queue") }))
+        .route("/queue", get(|| async { Html(include_str!("../static/queue.html")) }))
+        .route("/queue/join", post(join))
+        .route("/queue/leave", post(leave))
+        .route("/queue/status", get(status))
+        .with_state(state)
+}
+
+async fn join(State(state): State<WebState>) -> Result<Json<Ticket>, StatusCode> {
+    let ticket = new_ticket().map_err(|e| {
+        warn!("Could not create a ticket: {e:#}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    state.tx.send(QueueMessage::Join { player: PlayerId::Web(ticket), reply: reply_tx }).await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    reply_rx.await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(Ticket { ticket }))
+}
+
+async fn leave(State(state): State<WebState>, Json(Ticket { ticket }): Json<Ticket>) -> StatusCode {
+    match state.tx.send(QueueMessage::Leave { player: PlayerId::Web(ticket) }).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn status(State(state): State<WebState>, Query(Ticket { ticket }): Query<Ticket>) -> Json<TicketStatus> {
+    let queue = state.queue_rx.borrow();
+    let player = PlayerId::Web(ticket);
+    let now = Instant::now();
+    let remaining = queue.current.as_ref().map_or(Duration::ZERO, |turn| turn.ends_at.saturating_duration_since(now));
+
+    let status = if let Some(turn) = queue.current.as_ref().filter(|turn| turn.entry.player == player) {
+        TicketStatus::Playing { number: turn.entry.number, remaining_secs: remaining.as_secs() }
+    } else if let Some((ahead, entry)) = queue.waiting.iter().enumerate().find(|(_, entry)| entry.player == player) {
+        // Assumes that everyone plays their full turn
+        let wait = remaining + state.length * ahead as u32;
+        TicketStatus::Waiting { number: entry.number, ahead, wait_secs: wait.as_secs() }
+    } else {
+        TicketStatus::Expired
+    };
+    Json(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::message::{HandoverMessage, PlayerId};
+
+    use super::Queue;
+
+    const LENGTH: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn advance() {
+        let mut queue = Queue::default();
+        let (a, b) = (PlayerId::Web(1), PlayerId::Lighthouse("b".to_owned()));
+        assert_eq!(queue.advance(None, LENGTH), None);
+        assert_eq!(queue.join(a.clone(), false), 1);
+        assert_eq!(queue.join(b.clone(), true), 2);
+        assert_eq!(queue.join(a.clone(), true), 1);
+
+        let before = Instant::now();
+        let Some(HandoverMessage::Start { previous: None, player, number: 1, ends_at }) = queue.advance(None, LENGTH) else {
+            panic!("Expected the turn of the first player to start");
+        };
+        assert_eq!(player, a);
+        assert!(ends_at >= (before + LENGTH).into_std() && ends_at <= (Instant::now() + LENGTH).into_std());
+        // Joining via the bridge later on marks the player as remote
+        assert!(queue.current.as_ref().unwrap().entry.remote);
+
+        assert!(matches!(
+            queue.advance(Some(a.clone()), LENGTH),
+            Some(HandoverMessage::Start { previous: Some(previous), number: 2, .. }) if previous == a,
+        ));
+        assert_eq!(queue.advance(Some(b.clone()), LENGTH), Some(HandoverMessage::End { previous: b }));
+    }
+
+    #[test]
+    fn leave() {
+        let mut queue = Queue::default();
+        let (a, b) = (PlayerId::Web(1), PlayerId::Web(2));
+        queue.join(a.clone(), false);
+        queue.join(b.clone(), false);
+        queue.advance(None, LENGTH);
+        assert_eq!(queue.leave(&b), None);
+        assert_eq!(queue.leave(&a).map(|entry| entry.number), Some(1));
+        assert_eq!(queue, Queue { last_number: 2, ..Queue::default() });
+        // Numbers keep counting up
+        assert_eq!(queue.join(b, false), 3);
+    }
+
+    #[test]
+    fn deadline() {
+        let mut queue = Queue::default();
+        assert_eq!(queue.deadline(None), None);
+        queue.join(PlayerId::Web(1), false);
+        queue.advance(None, LENGTH);
+
+        let turn = queue.current.as_mut().unwrap();
+        let ends_at = turn.ends_at;
+        turn.last_input = ends_at - Duration::from_secs(50);
+        assert_eq!(queue.deadline(None), Some(ends_at));
+        assert_eq!(queue.deadline(Some(Duration::from_secs(20))), Some(ends_at - Duration::from_secs(30)));
+        // An idle timeout after the end of the turn doesn't extend it
+        assert_eq!(queue.deadline(Some(Duration::from_secs(100))), Some(ends_at));
+    }
+}
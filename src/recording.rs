@@ -0,0 +1,225 @@
+//! A compact file format for recordings of lighthouse frames.
+//!
+//! A recording starts with the magic bytes `LDFR` and a version byte, followed
+//! by one record per frame:
+//!
+//! - the time since the previous frame in milliseconds (`u32`, little-endian)
+//! - the length of the encoded frame in bytes (`u16`, little-endian)
+//! - the encoded frame, i.e. the frame XORed with the previous one (starting
+//!   with an empty frame) and run-length encoded as pairs of a count and a byte
+//!
+//! Since consecutive frames tend to be similar, most bytes of the XORed frame
+//! are zero and the runs are long.
+
+use std::{fs::File, future::Future, io::{self, BufReader, BufWriter, ErrorKind, Read, Write}, path::{Path, PathBuf}, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use lighthouse_client::protocol::{Frame, LIGHTHOUSE_BYTES};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::{info, warn};
+
+use crate::message::UpdaterMessage;
+
+const MAGIC: &[u8; 4] = b"LDFR";
+const VERSION: u8 = 1;
+
+/// Settings for recording the session.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Recording")]
+pub struct RecordingOptions {
+    /// A file to record the frames sent to the lighthouse to (replay with the replay subcommand).
+    #[arg(long, env = "LIDOOM_RECORD_FRAMES")]
+    pub record_frames: Option<PathBuf>,
+}
+
+/// Writes frames to a recording.
+pub struct FrameWriter<W> {
+    writer: W,
+    previous: [u8; LIGHTHOUSE_BYTES],
+}
+
+impl<W> FrameWriter<W> where W: Write {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(Self { writer, previous: [0; LIGHTHOUSE_BYTES] })
+    }
+
+    /// Writes a frame that was shown the given time after the previous one.
+    pub fn write(&mut self, delay: Duration, frame: Frame) -> Result<()> {
+        let bytes: [u8; LIGHTHOUSE_BYTES] = frame.into();
+        let mut encoded = Vec::new();
+        let mut xored = bytes.iter().zip(self.previous).map(|(b, p)| b ^ p).peekable();
+        while let Some(byte) = xored.next() {
+            let mut count = 1u8;
+            while count < u8::MAX && xored.next_if_eq(&byte).is_some() {
+                count += 1;
+            }
+            encoded.extend([count, byte]);
+        }
+
+        let delay_ms = u32::try_from(delay.as_millis()).unwrap_or(u32::MAX);
+        self.writer.write_all(&delay_ms.to_le_bytes())?;
+        self.writer.write_all(&(encoded.len() as u16).to_le_bytes())?;
+        self.writer.write_all(&encoded)?;
+        self.writer.flush()?;
+        self.previous = bytes;
+        Ok(())
+    }
+}
+
+/// Reads frames from a recording.
+pub struct FrameReader<R> {
+    reader: R,
+    previous: [u8; LIGHTHOUSE_BYTES],
+}
+
+impl<R> FrameReader<R> where R: Read {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header).context("Could not read header")?;
+        if &header[..4] != MAGIC {
+            bail!("Not a frame recording");
+        }
+        if header[4] != VERSION {
+            bail!("Unsupported recording version {}", header[4]);
+        }
+        Ok(Self { reader, previous: [0; LIGHTHOUSE_BYTES] })
+    }
+
+    /// Reads the next frame along with its delay after the previous one.
+    /// Returns `None` at the end of the recording.
+    pub fn read(&mut self) -> Result<Option<(Duration, Frame)>> {
+        let mut delay_ms = [0; 4];
+        match self.reader.read_exact(&mut delay_ms) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let mut length = [0; 2];
+        self.reader.read_exact(&mut length)?;
+        let mut encoded = vec![0; u16::from_le_bytes(length) as usize];
+        self.reader.read_exact(&mut encoded)?;
+
+        let mut bytes = self.previous;
+        let mut i = 0;
+        for pair in encoded.chunks_exact(2) {
+            let (count, byte) = (pair[0] as usize, pair[1]);
+            if i + count > LIGHTHOUSE_BYTES {
+                bail!("Frame is too long");
+            }
+            for b in &mut bytes[i..(i + count)] {
+                *b ^= byte;
+            }
+            i += count;
+        }
+        if i != LIGHTHOUSE_BYTES {
+            bail!("Frame is too short");
+        }
+
+        self.previous = bytes;
+        let delay = Duration::from_millis(u32::from_le_bytes(delay_ms) as u64);
+        Ok(Some((delay, Frame::from(bytes))))
+    }
+}
+
+/// Reads all frames of the recording at the given path.
+pub fn read_all(path: &Path) -> Result<Vec<(Duration, Frame)>> {
+    let file = File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let mut reader = FrameReader::new(BufReader::new(file))?;
+    let mut frames = Vec::new();
+    loop {
+        match reader.read() {
+            Ok(Some(frame)) => frames.push(frame),
+            Ok(None) => break,
+            // Recordings may be cut off if lidoom didn't exit cleanly
+            Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof) => {
+                warn!("Recording ends with an incomplete frame, ignoring it");
+                break;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(frames)
+}
+
+/// Creates the recording, returning the task that records the frames to it.
+pub fn start(rx: mpsc::Receiver<UpdaterMessage>, path: PathBuf) -> Result<impl Future<Output = Result<()>>> {
+    let file = File::create(&path).with_context(|| format!("Could not create {}", path.display()))?;
+    let writer = FrameWriter::new(BufWriter::new(file))?;
+    info!("Recording frames to {}", path.display());
+    Ok(run(rx, writer))
+}
+
+async fn run(mut rx: mpsc::Receiver<UpdaterMessage>, mut writer: FrameWriter<BufWriter<File>>) -> Result<()> {
+    let mut last_time: Option<Instant> = None;
+    while let Some(UpdaterMessage::Frame(frame)) = rx.recv().await {
+        let now = Instant::now();
+        let delay = last_time.map(|t| now - t).unwrap_or_default();
+        last_time = Some(now);
+        writer.write(delay, *frame.lighthouse())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use lighthouse_client::protocol::{Frame, LIGHTHOUSE_BYTES};
+
+    use super::{FrameReader, FrameWriter};
+
+    fn frame(seed: usize) -> Frame {
+        // Runs of varying length, including some longer than a count can hold
+        let bytes: [u8; LIGHTHOUSE_BYTES] = std::array::from_fn(|i| ((i / (seed * 100 + 1) + seed) % 7) as u8);
+        Frame::from(bytes)
+    }
+
+    #[test]
+    fn round_trip() {
+        let frames = [
+            (Duration::ZERO, Frame::empty()),
+            (Duration::from_millis(28), frame(0)),
+            (Duration::from_millis(29), frame(0)),
+            (Duration::from_millis(1000), frame(3)),
+            (Duration::from_millis(5), frame(1)),
+        ];
+        let mut writer = FrameWriter::new(Vec::new()).unwrap();
+        for &(delay, frame) in &frames {
+            writer.write(delay, frame).unwrap();
+        }
+
+        let mut reader = FrameReader::new(writer.writer.as_slice()).unwrap();
+        for &expected in &frames {
+            assert_eq!(reader.read().unwrap(), Some(expected));
+        }
+        assert_eq!(reader.read().unwrap(), None);
+    }
+
+    #[test]
+    fn unchanged_frames_are_tiny() {
+        let mut writer = FrameWriter::new(Vec::new()).unwrap();
+        writer.write(Duration::ZERO, frame(2)).unwrap();
+        let before = writer.writer.len();
+        writer.write(Duration::ZERO, frame(2)).unwrap();
+        // The delay, the length and a few runs of zeros
+        assert!(writer.writer.len() - before <= 4 + 2 + 2 * LIGHTHOUSE_BYTES.div_ceil(255));
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(FrameReader::new(&b"LDFX\x01"[..]).is_err());
+        assert!(FrameReader::new(&b"LDFR\x02"[..]).is_err());
+        assert!(FrameReader::new(&b"LD"[..]).is_err());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut writer = FrameWriter::new(Vec::new()).unwrap();
+        writer.write(Duration::ZERO, frame(1)).unwrap();
+        let bytes = &writer.writer[..writer.writer.len() - 1];
+        assert!(FrameReader::new(bytes).unwrap().read().is_err());
+    }
+}
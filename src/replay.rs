@@ -0,0 +1,53 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{bail, Result};
+use clap::Args;
+use tokio::{sync::mpsc, time::{self, Instant}};
+use tracing::info;
+
+use crate::{framebuffer::FramePool, message::UpdaterMessage, recording};
+
+/// Settings for replaying a recording.
+#[derive(Debug, Clone, Args)]
+pub struct ReplayOptions {
+    /// The recording to replay, as created with --record-frames.
+    pub file: PathBuf,
+    /// Whether to start over at the end of the recording.
+    #[arg(long = "loop")]
+    pub looping: bool,
+    /// The playback speed, e.g. 2 for twice the original speed.
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f64,
+}
+
+pub async fn run(txs: Vec<mpsc::Sender<UpdaterMessage>>, options: ReplayOptions) -> Result<()> {
+    if !(options.speed > 0.0 && options.speed.is_finite()) {
+        bail!("Speed must be positive, but was {}", options.speed);
+    }
+
+    let frames = recording::read_all(&options.file)?;
+    let total: Duration = frames.iter().map(|(delay, _)| *delay).sum();
+    info!("Replaying {} frames ({:.1} s) from {}", frames.len(), total.as_secs_f64(), options.file.display());
+    if frames.is_empty() {
+        return Ok(());
+    }
+
+    let mut pool = FramePool::new();
+    loop {
+        // Schedule relative to the start to avoid accumulating drift
+        let start = Instant::now();
+        let mut elapsed = Duration::ZERO;
+        for (delay, frame) in &frames {
+            elapsed += delay.div_f64(options.speed);
+            time::sleep_until(start + elapsed).await;
+            let frame = pool.write_lighthouse(frame);
+            for tx in &txs {
+                tx.send(UpdaterMessage::Frame(frame.clone())).await?;
+            }
+        }
+        if !options.looping {
+            break;
+        }
+    }
+    Ok(())
+}
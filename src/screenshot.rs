@@ -0,0 +1,67 @@
+use std::{fs::{self, File}, io::BufWriter, path::{Path, PathBuf}};
+
+use anyhow::Result;
+use chrono::Local;
+use clap::Args;
+use lighthouse_client::protocol::{LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS};
+use tokio::{sync::mpsc, task};
+use tracing::{info, warn};
+
+use crate::{constants::{DOOM_HEIGHT, DOOM_WIDTH}, facade::FacadeSettings, framebuffer::FrameBuffer, message::ScreenshotMessage};
+
+/// Settings for where and how screenshots are saved.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Screenshots")]
+pub struct ScreenshotOptions {
+    /// The directory to save screenshots to.
+    #[arg(long, env = "LIDOOM_SCREENSHOT_DIR", default_value = "screenshots")]
+    pub screenshot_dir: PathBuf,
+    /// Whether to render the lighthouse screenshot as the facade (see --facade) instead of at 28x14.
+    #[arg(long, env = "LIDOOM_SCREENSHOT_FACADE")]
+    pub screenshot_facade: bool,
+}
+
+pub async fn run(
+    mut rx: mpsc::Receiver<ScreenshotMessage>,
+    options: ScreenshotOptions,
+    facade: FacadeSettings,
+) -> Result<()> {
+    while let Some(ScreenshotMessage::Capture(frame)) = rx.recv().await {
+        // Encoding is CPU-bound, so we'll do it off the async threads
+        let options = options.clone();
+        match task::spawn_blocking(move || save(&frame, &options, &facade)).await? {
+            Ok(paths) => info!("Saved screenshot to {} and {}", paths[0].display(), paths[1].display()),
+            Err(e) => warn!("Could not save screenshot: {e:#}"),
+        }
+    }
+    Ok(())
+}
+
+/// Saves the full DOOM image and the lighthouse frame, returning their paths.
+fn save(frame: &FrameBuffer, options: &ScreenshotOptions, facade: &FacadeSettings) -> Result<[PathBuf; 2]> {
+    fs::create_dir_all(&options.screenshot_dir)?;
+    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S%.3f");
+    let doom_path = options.screenshot_dir.join(format!("lidoom_{timestamp}.png"));
+    let lighthouse_path = options.screenshot_dir.join(format!("lidoom_{timestamp}_lighthouse.png"));
+
+    write_png(&doom_path, DOOM_WIDTH, DOOM_HEIGHT, &frame.rgb())?;
+
+    if options.screenshot_facade {
+        let (width, height) = facade.size();
+        write_png(&lighthouse_path, width, height, &facade.render(frame.lighthouse()))?;
+    } else {
+        write_png(&lighthouse_path, LIGHTHOUSE_COLS, LIGHTHOUSE_ROWS, &Vec::from(*frame.lighthouse()))?;
+    }
+
+    Ok([doom_path, lighthouse_path])
+}
+
+/// Writes RGB24 pixels in row-major order to a PNG file.
+fn write_png(path: &Path, width: usize, height: usize, rgb: &[u8]) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgb)?;
+    Ok(())
+}
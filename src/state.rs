@@ -0,0 +1,231 @@
+//! Read-only access to DOOM's global game state.
+//!
+//! The engine keeps its state in C globals that are only ever mutated from the
+//! DOOM thread, so the functions in this module must only be called from that
+//! thread, e.g. from within the `DoomGeneric` callbacks.
+
+use std::{os::raw::{c_char, c_int, c_short, c_void}, ptr};
+
+use serde::Serialize;
+
+const MAXPLAYERS: usize = 4;
+const NUMPOWERS: usize = 6;
+const NUMCARDS: usize = 6;
+const NUMWEAPONS: usize = 9;
+const NUMAMMO: usize = 4;
+const NUMPSPRITES: usize = 2;
+/// The `playerstate_t` of a dead player.
+const PST_DEAD: c_int = 1;
+/// The `GameMode_t` of DOOM II, which names its maps differently.
+const COMMERCIAL: c_int = 2;
+/// The tics per second of the game loop.
+const TICRATE: c_int = 35;
+/// The names of the weapons, in the order of `weapontype_t`.
+const WEAPONS: [&str; NUMWEAPONS] = ["fist", "pistol", "shotgun", "chaingun", "rocket_launcher", "plasma_rifle", "bfg9000", "chainsaw", "super_shotgun"];
+/// The `ammotype_t` that each weapon uses, if any.
+const WEAPON_AMMO: [Option<usize>; NUMWEAPONS] = [None, Some(0), Some(1), Some(0), Some(3), Some(2), Some(2), None, Some(1)];
+
+/// Mirrors `ticcmd_t` from `d_ticcmd.h`.
+#[repr(C)]
+#[allow(dead_code)]
+struct TicCmd {
+    forwardmove: i8,
+    sidemove: i8,
+    angleturn: c_short,
+    chatchar: u8,
+    buttons: u8,
+    consistancy: u8,
+    buttons2: u8,
+    inventory: c_int,
+    lookfly: u8,
+    arti: u8,
+}
+
+/// Mirrors `pspdef_t` from `p_pspr.h`.
+#[repr(C)]
+#[allow(dead_code)]
+struct PspDef {
+    state: *const c_void,
+    tics: c_int,
+    sx: c_int,
+    sy: c_int,
+}
+
+/// Mirrors `player_t` from `d_player.h`. Enums and `boolean`s are `int`-sized.
+#[repr(C)]
+#[allow(dead_code)]
+struct Player {
+    mo: *const c_void,
+    playerstate: c_int,
+    cmd: TicCmd,
+    viewz: c_int,
+    viewheight: c_int,
+    deltaviewheight: c_int,
+    bob: c_int,
+    health: c_int,
+    armorpoints: c_int,
+    armortype: c_int,
+    powers: [c_int; NUMPOWERS],
+    cards: [c_int; NUMCARDS],
+    backpack: c_int,
+    frags: [c_int; MAXPLAYERS],
+    readyweapon: c_int,
+    pendingweapon: c_int,
+    weaponowned: [c_int; NUMWEAPONS],
+    ammo: [c_int; NUMAMMO],
+    maxammo: [c_int; NUMAMMO],
+    attackdown: c_int,
+    usedown: c_int,
+    cheats: c_int,
+    refire: c_int,
+    killcount: c_int,
+    itemcount: c_int,
+    secretcount: c_int,
+    message: *const c_char,
+    damagecount: c_int,
+    bonuscount: c_int,
+    attacker: *const c_void,
+    extralight: c_int,
+    fixedcolormap: c_int,
+    colormap: c_int,
+    psprites: [PspDef; NUMPSPRITES],
+    didsecret: c_int,
+}
+
+extern "C" {
+    static players: [Player; MAXPLAYERS];
+    static consoleplayer: c_int;
+    static gamemode: c_int;
+    static gamestate: c_int;
+    static demoplayback: c_int;
+    static gameskill: c_int;
+    static gameepisode: c_int;
+    static gamemap: c_int;
+    static leveltime: c_int;
+    static gametic: c_int;
+    static totalkills: c_int;
+    static totalitems: c_int;
+    static totalsecret: c_int;
+}
+
+/// A snapshot of the local player's status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerStatus {
+    pub health: i32,
+    /// The amount of recently taken damage, decays over time.
+    pub damage_count: i32,
+    /// Whether the player is dead and waiting to respawn.
+    pub dead: bool,
+}
+
+/// Reads the status of the local player.
+pub fn player_status() -> PlayerStatus {
+    // SAFETY: The globals are only mutated by the DOOM thread, which we are
+    // running on (see the module docs). `consoleplayer` is always a valid
+    // index into `players`.
+    unsafe {
+        let player = ptr::addr_of!(players[consoleplayer as usize]);
+        PlayerStatus {
+            health: (*player).health,
+            damage_count: (*player).damagecount,
+            dead: (*player).playerstate == PST_DEAD,
+        }
+    }
+}
+
+/// What the game is showing, mirrors `gamestate_t` from `doomdef.h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Screen {
+    Level,
+    Intermission,
+    Finale,
+    /// The title screen or another page between demos (`GS_DEMOSCREEN`).
+    Title,
+}
+
+/// The ammo the player carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Ammo {
+    pub bullets: i32,
+    pub shells: i32,
+    pub rockets: i32,
+    pub cells: i32,
+}
+
+/// How many of something the player found, out of how many there are in the
+/// level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Tally {
+    pub count: i32,
+    pub total: i32,
+}
+
+/// A snapshot of the game, e.g. for showing scoreboards next to the building.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GameState {
+    pub screen: Screen,
+    /// Whether a demo is being played back rather than someone playing.
+    pub demo: bool,
+    /// The current (or last) map, e.g. `E1M1` or `MAP01`.
+    pub map: String,
+    /// The skill from 1 (I'm too young to die) to 5 (Nightmare!).
+    pub skill: i32,
+    pub health: i32,
+    pub armor: i32,
+    pub dead: bool,
+    pub weapon: &'static str,
+    /// The ammo of the current weapon, if it uses any.
+    pub ammo: Option<i32>,
+    pub all_ammo: Ammo,
+    pub kills: Tally,
+    pub items: Tally,
+    pub secrets: Tally,
+    /// The time spent in the current level.
+    pub time_secs: i32,
+    /// The number of the player whose turn it is, if the player queue is used.
+    pub player: Option<u64>,
+}
+
+/// The number of tics that the game loop has run since DOOM started.
+pub fn game_tic() -> u64 {
+    // SAFETY: See `player_status`.
+    unsafe { gametic as u64 }
+}
+
+/// Reads a snapshot of the game, leaving the player to be filled in by the
+/// caller.
+pub fn game_state() -> GameState {
+    // SAFETY: See `player_status`.
+    unsafe {
+        let player = ptr::addr_of!(players[consoleplayer as usize]);
+        let ammo = (*player).ammo;
+        let weapon = usize::try_from((*player).readyweapon).ok().filter(|&i| i < NUMWEAPONS);
+        GameState {
+            screen: match gamestate {
+                1 => Screen::Intermission,
+                2 => Screen::Finale,
+                3 => Screen::Title,
+                _ => Screen::Level,
+            },
+            demo: demoplayback != 0,
+            map: if gamemode == COMMERCIAL {
+                format!("MAP{gamemap:02}")
+            } else {
+                format!("E{gameepisode}M{gamemap}")
+            },
+            skill: gameskill + 1,
+            health: (*player).health,
+            armor: (*player).armorpoints,
+            dead: (*player).playerstate == PST_DEAD,
+            weapon: weapon.map_or("none", |i| WEAPONS[i]),
+            ammo: weapon.and_then(|i| WEAPON_AMMO[i]).map(|i| ammo[i]),
+            all_ammo: Ammo { bullets: ammo[0], shells: ammo[1], rockets: ammo[3], cells: ammo[2] },
+            kills: Tally { count: (*player).killcount, total: totalkills },
+            items: Tally { count: (*player).itemcount, total: totalitems },
+            secrets: Tally { count: (*player).secretcount, total: totalsecret },
+            time_secs: leveltime / TICRATE,
+            player: None,
+        }
+    }
+}
@@ -1,15 +1,39 @@
 use anyhow::Result;
 use lighthouse_client::{Lighthouse, TokioWebSocket};
 use tokio::sync::mpsc;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use crate::message::UpdaterMessage;
+use crate::message::{StateMessage, UpdaterMessage};
 
-pub async fn run(lh: Lighthouse<TokioWebSocket>, mut rx: mpsc::Receiver<UpdaterMessage>) -> Result<()> {
-    while let Some(UpdaterMessage::Frame(frame)) = rx.recv().await {
-        // Send the rendered frame to the lighthouse
-        lh.put_model(frame).await?;
-        debug!("Sent frame");
+/// Sends the rendered frames to the lighthouse and, if given a channel and a
+/// resource path, publishes the game state alongside them.
+pub async fn run(
+    lh: Lighthouse<TokioWebSocket>,
+    mut rx: mpsc::Receiver<UpdaterMessage>,
+    mut state: Option<(mpsc::Receiver<StateMessage>, Vec<String>)>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(UpdaterMessage::Frame(frame)) => {
+                    // Send the rendered frame to the lighthouse
+                    lh.put_model(*frame.lighthouse()).await?;
+                    debug!("Sent frame");
+                },
+                None => break,
+            },
+            message = async { state.as_mut().unwrap().0.recv().await }, if state.is_some() => match message {
+                Some(StateMessage::Publish(game_state)) => {
+                    let path = &state.as_ref().unwrap().1;
+                    // POST creates the resource if it doesn't exist yet
+                    match lh.post(path, game_state).await {
+                        Ok(_) => debug!("Published game state"),
+                        Err(e) => warn!("Could not publish the game state to {}: {e}", path.join("/")),
+                    }
+                },
+                None => state = None,
+            },
+        }
     }
     Ok(())
 }
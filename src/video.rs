@@ -0,0 +1,84 @@
+use std::{future::Future, path::PathBuf, process::Stdio, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use tokio::{io::AsyncWriteExt, process::{Child, ChildStdin, Command}, sync::mpsc, time::{self, MissedTickBehavior}};
+use tracing::{info, warn};
+
+use crate::{constants::{DOOM_HEIGHT, DOOM_TICK_RATE, DOOM_WIDTH}, message::UpdaterMessage};
+
+/// The raw pixel format that DOOM's XRGB8888 pixels have in memory.
+const PIXEL_FORMAT: &str = if cfg!(target_endian = "little") { "bgr0" } else { "0rgb" };
+
+/// Settings for capturing the full-resolution gameplay as a video.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Recording")]
+pub struct VideoOptions {
+    /// A video file (e.g. .mp4 or .webm) to capture the gameplay to using ffmpeg.
+    #[arg(long, env = "LIDOOM_VIDEO")]
+    pub video: Option<PathBuf>,
+    /// The command that encodes the video from raw frames on stdin. {format}, {size}, {rate} and {output} are substituted.
+    #[arg(long, env = "LIDOOM_VIDEO_COMMAND", default_value = "ffmpeg -y -loglevel error -f rawvideo -pixel_format {format} -video_size {size} -framerate {rate} -i - -pix_fmt yuv420p {output}")]
+    pub video_command: String,
+}
+
+/// Spawns the video encoder, returning the task that feeds the frames to it.
+/// Must be called within the Tokio runtime.
+pub fn start(rx: mpsc::Receiver<UpdaterMessage>, options: VideoOptions, path: PathBuf) -> Result<impl Future<Output = Result<()>>> {
+    let size = format!("{DOOM_WIDTH}x{DOOM_HEIGHT}");
+    let rate = DOOM_TICK_RATE.to_string();
+    let output = path.to_string_lossy();
+    // Substitute after splitting, so the output path may contain spaces
+    let args: Vec<String> = options.video_command.split_whitespace()
+        .map(|arg| arg
+            .replace("{format}", PIXEL_FORMAT)
+            .replace("{size}", &size)
+            .replace("{rate}", &rate)
+            .replace("{output}", &output))
+        .collect();
+    let Some((program, args)) = args.split_first() else {
+        bail!("The video command is empty");
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not spawn {program}"))?;
+    let stdin = child.stdin.take().context("Could not open stdin of the video encoder")?;
+    info!("Capturing video to {}", path.display());
+    Ok(run(rx, child, stdin))
+}
+
+async fn run(mut rx: mpsc::Receiver<UpdaterMessage>, mut child: Child, mut stdin: ChildStdin) -> Result<()> {
+    // The video has a fixed frame rate, so we write the latest frame at the
+    // engine's tick rate, repeating it if the engine stalls and catching up
+    // with the wall clock if the encoder does.
+    let Some(UpdaterMessage::Frame(mut frame)) = rx.recv().await else {
+        return Ok(());
+    };
+    let mut interval = time::interval(Duration::from_secs(1) / DOOM_TICK_RATE);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(UpdaterMessage::Frame(latest)) => frame = latest,
+                None => break,
+            },
+            _ = interval.tick() => {
+                if let Err(e) = stdin.write_all(frame.pixel_bytes()).await {
+                    warn!("Stopping video capture, the encoder exited: {e}");
+                    break;
+                }
+            },
+        }
+    }
+
+    // Closing stdin lets the encoder finish the file
+    drop(stdin);
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("The video encoder failed with {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,35 @@
+//! A web server for visitors, e.g. for joining the player queue.
+
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+
+use anyhow::{Context, Result};
+use axum::Router;
+use clap::Args;
+use tokio::net::TcpListener;
+use tracing::info;
+
+/// Settings for the web server.
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Web")]
+pub struct WebOptions {
+    /// The address to serve the web page on, e.g. 0.0.0.0:8080 (disabled by default).
+    #[arg(long, env = "LIDOOM_WEB")]
+    pub web: Option<SocketAddr>,
+}
+
+/// Binds to the given address upfront, so a taken address fails the startup
+/// rather than going unnoticed while the game runs.
+pub fn bind(address: SocketAddr) -> Result<StdTcpListener> {
+    let listener = StdTcpListener::bind(address).with_context(|| format!("Could not bind to {address}"))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serves the given routes on the given (bound) listener.
+pub async fn run(router: Router, listener: StdTcpListener) -> Result<()> {
+    let address = listener.local_addr()?;
+    let listener = TcpListener::from_std(listener)?;
+    info!("Serving the web page on http://{address}");
+    axum::serve(listener, router).await?;
+    Ok(())
+}